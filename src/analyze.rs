@@ -0,0 +1,263 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::Result;
+use pnet::packet::icmp::{IcmpPacket, IcmpTypes};
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::Packet;
+
+use crate::packet::icmp_checksum_ok;
+use crate::pcap::{read_capture, CapturedPacket};
+use crate::ping::print_statistics;
+use crate::stats::PingStats;
+
+// Which request/reply pair a probe used
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum ProbeKind {
+    Echo,
+    Timestamp,
+}
+
+impl fmt::Display for ProbeKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProbeKind::Echo => write!(f, "echo"),
+            ProbeKind::Timestamp => write!(f, "timestamp"),
+        }
+    }
+}
+
+// The probes one host sent with one identifier, which is what a single ping run looks like
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct SessionKey {
+    requester: Ipv4Addr,
+    identifier: u16,
+    kind: ProbeKind,
+}
+
+// A request seen in the capture and who has answered it
+struct Request {
+    sent_ns: u64,
+    responders: HashSet<Ipv4Addr>,
+}
+
+// Everything seen of one ping run
+struct Session {
+    target: Ipv4Addr,
+    first_ns: u64,
+    last_ns: u64,
+    requests: HashMap<u16, Request>,
+    stats: PingStats,
+    // Replies from hosts other than the first to answer, as with broadcast pings
+    other_responders: u64,
+}
+
+// An ICMP echo or timestamp message pulled out of a captured datagram
+struct Probe {
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    reply: bool,
+    kind: ProbeKind,
+    identifier: u16,
+    sequence: u16,
+    checksum_ok: bool,
+}
+
+// Parse a captured datagram as an echo or timestamp request or reply
+fn parse_probe(datagram: &[u8]) -> Option<Probe> {
+    let ip = Ipv4Packet::new(datagram)?;
+    // Only the first fragment carries the ICMP header
+    if ip.get_next_level_protocol().0 != 1 || ip.get_fragment_offset() != 0 {
+        return None;
+    }
+    let icmp = IcmpPacket::new(datagram.get(ip.get_header_length() as usize * 4..)?)?;
+    let (kind, reply) = match icmp.get_icmp_type() {
+        IcmpTypes::EchoRequest => (ProbeKind::Echo, false),
+        IcmpTypes::EchoReply => (ProbeKind::Echo, true),
+        IcmpTypes::Timestamp => (ProbeKind::Timestamp, false),
+        IcmpTypes::TimestampReply => (ProbeKind::Timestamp, true),
+        _ => return None,
+    };
+    let payload = icmp.payload();
+    let header = payload.get(..4)?;
+    Some(Probe {
+        source: ip.get_source(),
+        destination: ip.get_destination(),
+        reply,
+        kind,
+        identifier: u16::from_be_bytes([header[0], header[1]]),
+        sequence: u16::from_be_bytes([header[2], header[3]]),
+        checksum_ok: icmp_checksum_ok(&icmp),
+    })
+}
+
+// The ping runs in a capture, in the order they started, and the totals across them
+struct Analysis {
+    requests: u64,
+    replies: u64,
+    unmatched: u64,
+    sessions: Vec<(SessionKey, Session)>,
+}
+
+// Pair up the requests and replies in a capture into ping runs
+fn analyze_packets(packets: &[CapturedPacket]) -> Analysis {
+    let mut sessions: HashMap<SessionKey, Session> = HashMap::new();
+    let mut unmatched = 0;
+    let (mut requests, mut replies) = (0, 0);
+
+    for packet in packets {
+        let Some(probe) = parse_probe(&packet.datagram) else {
+            continue;
+        };
+        let time_ns = packet.time_ns;
+
+        if !probe.reply {
+            requests += 1;
+            let key = SessionKey { requester: probe.source, identifier: probe.identifier, kind: probe.kind };
+            let session = sessions.entry(key).or_insert_with(|| Session {
+                target: probe.destination,
+                first_ns: time_ns,
+                last_ns: time_ns,
+                requests: HashMap::new(),
+                stats: PingStats::new(),
+                other_responders: 0,
+            });
+            // A sequence number seen again belongs to a new probe, after wrapping or a restart
+            session.requests.insert(probe.sequence, Request { sent_ns: time_ns, responders: HashSet::new() });
+            session.stats.packets_sent += 1;
+            session.last_ns = session.last_ns.max(time_ns);
+            continue;
+        }
+
+        replies += 1;
+        let key = SessionKey { requester: probe.destination, identifier: probe.identifier, kind: probe.kind };
+        let Some(session) = sessions.get_mut(&key) else {
+            unmatched += 1;
+            continue;
+        };
+        let Some(request) = session.requests.get_mut(&probe.sequence).filter(|request| request.sent_ns <= time_ns) else {
+            unmatched += 1;
+            continue;
+        };
+        session.last_ns = session.last_ns.max(time_ns);
+        if !probe.checksum_ok {
+            session.stats.corrupted += 1;
+        }
+
+        let rtt = (time_ns - request.sent_ns) as f64 / 1_000_000.0;
+        if request.responders.is_empty() {
+            session.stats.update(rtt);
+        } else if request.responders.contains(&probe.source) {
+            session.stats.duplicates += 1;
+        } else {
+            session.other_responders += 1;
+        }
+        request.responders.insert(probe.source);
+    }
+
+    // Runs in the order they started
+    let mut sessions: Vec<(SessionKey, Session)> = sessions.into_iter().collect();
+    sessions.sort_by_key(|(_, session)| session.first_ns);
+    Analysis { requests, replies, unmatched, sessions }
+}
+
+// Reconstruct request/reply pairs from a capture and print the statistics each ping run would have
+pub fn analyze(path: &Path) -> Result<()> {
+    let packets = read_capture(path)?;
+    let analysis = analyze_packets(&packets);
+
+    println!("ANALYZE {}: {} IPv4 packets, {} requests, {} replies, {} ping runs",
+             path.display(), packets.len(), analysis.requests, analysis.replies, analysis.sessions.len());
+
+    for (key, session) in &analysis.sessions {
+        println!("\n{} -> {} icmp {} id={}", key.requester, session.target, key.kind, key.identifier);
+        print_statistics(&session.target.to_string(), &session.stats,
+                         Duration::from_nanos(session.last_ns - session.first_ns));
+        if session.other_responders > 0 {
+            println!("{} further replies from other hosts", session.other_responders);
+        }
+    }
+    if analysis.unmatched > 0 {
+        println!("\n{} replies without a request in the capture", analysis.unmatched);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet::packet::icmp::{IcmpCode, IcmpType};
+
+    use crate::packet::{build_icmp_message, build_ipv4_datagram};
+
+    const HOST: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 10);
+    const TARGET: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 1);
+
+    // A captured ICMP message with the given identifier and sequence, `ms` milliseconds in
+    fn captured(ms: u64, source: Ipv4Addr, destination: Ipv4Addr, icmp_type: IcmpType,
+                identifier: u16, sequence: u16) -> CapturedPacket {
+        let mut header = [0u8; 4];
+        header[..2].copy_from_slice(&identifier.to_be_bytes());
+        header[2..].copy_from_slice(&sequence.to_be_bytes());
+        let mut message = [0u8; 64];
+        let length = build_icmp_message(&mut message, icmp_type, IcmpCode::new(0), header, &[0x5a; 12]);
+        CapturedPacket {
+            time_ns: ms * 1_000_000,
+            datagram: build_ipv4_datagram(source, destination, 64, 0, &[], &message[..length]),
+        }
+    }
+
+    fn request(ms: u64, sequence: u16) -> CapturedPacket {
+        captured(ms, HOST, TARGET, IcmpTypes::EchoRequest, 7, sequence)
+    }
+
+    fn reply(ms: u64, sequence: u16) -> CapturedPacket {
+        captured(ms, TARGET, HOST, IcmpTypes::EchoReply, 7, sequence)
+    }
+
+    #[test]
+    fn counts_loss_duplicates_and_jitter() {
+        let packets = [
+            request(0, 1), reply(10, 1),
+            request(1000, 2), reply(1030, 2), reply(1031, 2),
+            // No reply to the third
+            request(2000, 3),
+            request(3000, 4), reply(3020, 4),
+        ];
+        let analysis = analyze_packets(&packets);
+        assert_eq!((analysis.requests, analysis.replies, analysis.unmatched), (4, 4, 0));
+        assert_eq!(analysis.sessions.len(), 1);
+
+        let (key, session) = &analysis.sessions[0];
+        assert_eq!((key.requester, key.identifier, key.kind), (HOST, 7, ProbeKind::Echo));
+        assert_eq!(session.target, TARGET);
+        let stats = &session.stats;
+        assert_eq!((stats.packets_sent, stats.packets_received, stats.duplicates), (4, 3, 1));
+        assert_eq!(stats.packet_loss(), 25.0);
+        assert_eq!((stats.rtt_min, stats.rtt_max), (10.0, 30.0));
+        // |30 - 10| and |20 - 30|
+        assert_eq!(stats.jitter(), 15.0);
+        assert_eq!(session.last_ns - session.first_ns, 3_020_000_000);
+    }
+
+    #[test]
+    fn keeps_timestamp_probes_apart_from_echoes() {
+        let packets = [
+            request(0, 1),
+            captured(5, HOST, TARGET, IcmpTypes::Timestamp, 7, 1),
+            captured(8, TARGET, HOST, IcmpTypes::TimestampReply, 7, 1),
+            reply(12, 1),
+            // Answers nothing that was captured
+            captured(20, TARGET, HOST, IcmpTypes::TimestampReply, 7, 2),
+        ];
+        let analysis = analyze_packets(&packets);
+        assert_eq!((analysis.requests, analysis.replies, analysis.unmatched), (2, 3, 1));
+
+        let kinds: Vec<_> = analysis.sessions.iter().map(|(key, session)| (key.kind, session.stats.rtt_sum)).collect();
+        assert_eq!(kinds, [(ProbeKind::Echo, 12.0), (ProbeKind::Timestamp, 3.0)]);
+    }
+}
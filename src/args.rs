@@ -1,19 +1,36 @@
-use clap::Parser;
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+
+use crate::dns::DnsType;
+use crate::http::HttpMethod;
+use crate::image_art::ArtColor;
+#[cfg(target_os = "linux")]
+use crate::packet::{parse_dscp, parse_tos, Ecn, IpTimestampMode};
+use crate::simulate::parse_probability;
+#[cfg(target_os = "linux")]
+use crate::simulate::{parse_simulation, SimulationSpec};
+#[cfg(target_os = "linux")]
+use crate::socket::PmtuDiscovery;
 
 #[cfg(target_os = "linux")]
 #[derive(Parser, Debug)]
-#[clap(author, version, about)]
+#[clap(author, version, about, subcommand_negates_reqs = true)]
 pub struct Args {
-    /// Target host to ping
-    pub destination: String,
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
+    /// Target host to ping, an http:// URL, or the name to query with --dns
+    #[clap(required = true)]
+    pub destination: Option<String>,
 
     /// Number of packets to send
     #[clap(short = 'c', long = "count")]
     pub count: Option<u16>,
 
-    /// Number of bytes to send
+    /// Number of bytes to send, or a start:end:step range to sweep across probes
     #[clap(short = 's', long = "size")]
-    pub size: Option<u16>,
+    pub size: Option<String>,
 
     /// Interval between sending packets (in seconds)
     #[clap(short = 'i', long = "interval")]
@@ -43,8 +60,8 @@ pub struct Args {
     #[clap(short = 'a', long = "audible")]
     pub audible: bool,
 
-    /// Bypass route using socket options
-    #[clap(short = 'b')]
+    /// Bypass the routing table and send only to directly attached hosts
+    #[clap(short = 'b', long = "bypass-route")]
     pub bypass_route: bool,
 
     /// Numeric output only (no DNS resolution)
@@ -55,21 +72,119 @@ pub struct Args {
     #[clap(short = 'D', long = "timestamp")]
     pub timestamp: bool,
 
-    /// Flood ping
-    #[clap(short = 'f', long = "flood")]
-    pub flood: bool,
-    
     /// Use rainbow colors for ASCII art
     #[clap(short = 'r', long = "rainbow")]
     pub rainbow: bool,
+
+    /// Art to send and show: a text file, or a directory to pick one from (default: ~/.config/bingping/art if it exists)
+    #[clap(long = "art", env = "BINGPING_ART", global = true)]
+    pub art: Option<PathBuf>,
+
+    /// Pick the art with this name from the --art directory instead of at random
+    #[clap(long = "art-name", env = "BINGPING_ART_NAME", global = true)]
+    pub art_name: Option<String>,
+
+    /// Convert this PNG or JPEG image into the art, in place of --art
+    #[clap(long = "art-image", global = true)]
+    pub art_image: Option<PathBuf>,
+
+    /// Width in characters of art converted from an image
+    #[clap(long = "art-width", requires = "art_image", global = true, default_value_t = 80,
+           value_parser = clap::value_parser!(u32).range(1..=1000))]
+    pub art_width: u32,
+
+    /// Draw art converted from an image in colour blocks instead of characters
+    #[clap(long = "art-color", value_enum, requires = "art_image", global = true, default_value_t = ArtColor::None)]
+    pub art_color: ArtColor,
+
+    /// HTTP method used when the destination is an http:// URL
+    #[clap(long = "http-method", value_enum)]
+    pub http_method: Option<HttpMethod>,
+
+    /// Query this DNS server for the destination name instead of pinging it
+    #[clap(long = "dns")]
+    pub dns: Option<String>,
+
+    /// Record type to query in DNS mode
+    #[clap(long = "dns-type", value_enum, requires = "dns")]
+    pub dns_type: Option<DnsType>,
+
+    /// Send ICMP Timestamp Requests and estimate the remote clock offset
+    #[clap(long = "icmp-timestamp")]
+    pub icmp_timestamp: bool,
+
+    /// Path MTU discovery: do (set DF), want (set DF, fragment locally when needed) or dont
+    #[clap(short = 'M', value_enum)]
+    pub pmtu_discovery: Option<PmtuDiscovery>,
+
+    /// Record the route taken by each probe (IPv4 Record Route option)
+    #[clap(short = 'R', long = "record-route", conflicts_with = "ip_timestamp")]
+    pub record_route: bool,
+
+    /// Record hop timestamps in each probe (IPv4 Timestamp option)
+    #[clap(short = 'T', long = "ip-timestamp", value_enum)]
+    pub ip_timestamp: Option<IpTimestampMode>,
+
+    /// Type of service byte for probes, in decimal or 0x hex
+    #[clap(short = 'Q', long = "tos", value_parser = parse_tos, conflicts_with_all = ["dscp", "ecn"])]
+    pub tos: Option<u8>,
+
+    /// DSCP class for probes: EF, AF11-AF43, CS0-CS7, VA, LE or a number 0-63
+    #[clap(long = "dscp", value_parser = parse_dscp)]
+    pub dscp: Option<u8>,
+
+    /// ECN codepoint for probes
+    #[clap(long = "ecn", value_enum)]
+    pub ecn: Option<Ecn>,
+
+    /// Send from this interface name or source address
+    #[clap(short = 'I', long = "interface")]
+    pub interface: Option<String>,
+
+    /// Firewall mark for probes, used by policy routing
+    #[clap(short = 'm', long = "mark")]
+    pub mark: Option<u32>,
+
+    /// Allow pinging a broadcast address, counting replies from every host
+    #[clap(long = "broadcast")]
+    pub broadcast: bool,
+
+    /// Run inside the named network namespace (as created by `ip netns add`)
+    #[clap(long = "netns")]
+    pub netns: Option<String>,
+
+    /// Flood ping: send probes as fast as possible in batches and report packets per second and drops
+    #[clap(short = 'f', long = "benchmark", visible_alias = "flood",
+           conflicts_with_all = ["icmp_timestamp", "record_route", "ip_timestamp", "broadcast", "pcap"])]
+    pub benchmark: bool,
+
+    /// Probes per sendmmsg/recvmmsg call when flooding
+    #[clap(long = "batch", requires = "benchmark", value_parser = clap::value_parser!(u16).range(1..=1024))]
+    pub batch: Option<u16>,
+
+    /// Ping over a simulated network instead of raw sockets, e.g. latency=20,jitter=5,dist=normal,loss=10%,dup=1%,reorder=5%,corrupt=1%,seed=7
+    #[clap(long = "simulate", value_parser = parse_simulation, conflicts_with_all = ["benchmark", "dns"])]
+    pub simulate: Option<SimulationSpec>,
+
+    /// Write every probe sent and every ICMP packet received to this pcap file
+    #[clap(long = "pcap")]
+    pub pcap: Option<PathBuf>,
+
+    /// Send the art a slice per probe and reassemble the whole picture from the replies
+    #[clap(long = "chunked-art", conflicts_with_all = ["icmp_timestamp", "benchmark"])]
+    pub chunked_art: bool,
 }
 
 #[cfg(target_os = "windows")]
 #[derive(Parser, Debug)]
-#[clap(author, version, about)]
+#[clap(author, version, about, subcommand_negates_reqs = true)]
 pub struct Args {
-    /// Target host to ping
-    pub destination: String,
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
+    /// Target host to ping, an http:// URL, or the name to query with --dns
+    #[clap(required = true)]
+    pub destination: Option<String>,
 
     /// Number of packets to send
     #[clap(short = 'n', long = "count")]
@@ -91,11 +206,11 @@ pub struct Args {
     #[clap(short = 'i', long = "ttl")]
     pub ttl: Option<u8>,
 
-    /// Record route
-    #[clap(short = 'r', long = "record-route")]
+    /// Record route for up to 9 hops
+    #[clap(short = 'r', long = "record-route", conflicts_with = "timestamp")]
     pub record_route: bool,
 
-    /// Timestamp route
+    /// Timestamp route for up to 4 hops
     #[clap(short = 's', long = "timestamp")]
     pub timestamp: bool,
 
@@ -106,4 +221,70 @@ pub struct Args {
     /// Use rainbow colors for ASCII art
     #[clap(long = "rainbow")]
     pub rainbow: bool,
-} 
\ No newline at end of file
+
+    /// Art to send and show: a text file, or a directory to pick one from (default: ~/.config/bingping/art if it exists)
+    #[clap(long = "art", env = "BINGPING_ART", global = true)]
+    pub art: Option<PathBuf>,
+
+    /// Pick the art with this name from the --art directory instead of at random
+    #[clap(long = "art-name", env = "BINGPING_ART_NAME", global = true)]
+    pub art_name: Option<String>,
+
+    /// Convert this PNG or JPEG image into the art, in place of --art
+    #[clap(long = "art-image", global = true)]
+    pub art_image: Option<PathBuf>,
+
+    /// Width in characters of art converted from an image
+    #[clap(long = "art-width", requires = "art_image", global = true, default_value_t = 80,
+           value_parser = clap::value_parser!(u32).range(1..=1000))]
+    pub art_width: u32,
+
+    /// Draw art converted from an image in colour blocks instead of characters
+    #[clap(long = "art-color", value_enum, requires = "art_image", global = true, default_value_t = ArtColor::None)]
+    pub art_color: ArtColor,
+
+    /// HTTP method used when the destination is an http:// URL
+    #[clap(long = "http-method", value_enum)]
+    pub http_method: Option<HttpMethod>,
+
+    /// Query this DNS server for the destination name instead of pinging it
+    #[clap(long = "dns")]
+    pub dns: Option<String>,
+
+    /// Record type to query in DNS mode
+    #[clap(long = "dns-type", value_enum, requires = "dns")]
+    pub dns_type: Option<DnsType>,
+
+    /// Send ICMP Timestamp Requests and estimate the remote clock offset
+    #[clap(long = "icmp-timestamp")]
+    pub icmp_timestamp: bool,
+}
+
+// Alternative modes that replace normal pinging
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Discover the path MTU to a host
+    Pmtu {
+        /// Target host
+        host: String,
+    },
+    /// Summarise the ping runs in a pcap or pcapng capture
+    Analyze {
+        /// Capture file
+        file: PathBuf,
+    },
+    /// Answer echo requests with bingus art, as a reflector for lab tests
+    Serve {
+        /// Answer UDP datagrams on this port instead of ICMP echo requests
+        #[clap(long = "udp")]
+        udp: Option<u16>,
+
+        /// Delay before each reply (in ms)
+        #[clap(long = "delay", default_value_t = 0)]
+        delay: u64,
+
+        /// Share of requests to ignore, as 0-1 or a percentage
+        #[clap(long = "loss", value_parser = parse_probability)]
+        loss: Option<f64>,
+    },
+}
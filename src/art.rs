@@ -0,0 +1,282 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use rand::seq::SliceRandom;
+
+use crate::display::load_ascii_art;
+use crate::packet::{ECHO_TIMESTAMP_LEN, ICMP_HEADER_LEN};
+
+// Largest art accepted; chunked mode still sends it in a reasonable number of probes
+pub const MAX_ART_LEN: usize = 64 * 1024;
+
+// Marks echo data that carries a slice of art rather than the start of it
+const ART_CHUNK_MAGIC: [u8; 4] = *b"bArt";
+
+// Magic, then the offset of the slice and the length of the whole art
+pub const ART_CHUNK_HEADER_LEN: usize = 12;
+
+// Where the chunk starts in an echo request: after the ICMP header and the send time
+pub const ART_CHUNK_START: usize = ICMP_HEADER_LEN + ECHO_TIMESTAMP_LEN;
+
+// Smallest echo data with room for the send time, a chunk header and at least one byte of art
+pub const MIN_CHUNKED_SIZE: usize = ECHO_TIMESTAMP_LEN + ART_CHUNK_HEADER_LEN + 1;
+
+// Art bytes carried by each probe of `size` bytes of echo data
+pub fn chunk_size(size: usize) -> usize {
+    size.saturating_sub(ECHO_TIMESTAMP_LEN + ART_CHUNK_HEADER_LEN)
+}
+
+// One slice of art and where it goes
+pub struct ArtChunk<'a> {
+    pub offset: usize,
+    pub total: usize,
+    pub data: &'a [u8],
+}
+
+// Write the chunk starting at `offset` into `data`, which follows the send time, padding past
+// the end of the art with a pattern
+pub fn write_art_chunk(data: &mut [u8], art: &[u8], offset: usize) {
+    if data.len() < ART_CHUNK_HEADER_LEN {
+        return;
+    }
+    let (header, slice) = data.split_at_mut(ART_CHUNK_HEADER_LEN);
+    header[..4].copy_from_slice(&ART_CHUNK_MAGIC);
+    header[4..8].copy_from_slice(&(offset as u32).to_be_bytes());
+    header[8..12].copy_from_slice(&(art.len() as u32).to_be_bytes());
+
+    let art_slice = art.get(offset..).unwrap_or_default();
+    let len = art_slice.len().min(slice.len());
+    slice[..len].copy_from_slice(&art_slice[..len]);
+    slice[len..].fill(b'#');
+}
+
+// The chunk in echo data following the send time, if it carries one
+pub fn parse_art_chunk(data: &[u8]) -> Option<ArtChunk<'_>> {
+    let (header, slice) = data.split_at_checked(ART_CHUNK_HEADER_LEN)?;
+    if header[..4] != ART_CHUNK_MAGIC {
+        return None;
+    }
+    let offset = u32::from_be_bytes(header[4..8].try_into().unwrap()) as usize;
+    let total = u32::from_be_bytes(header[8..12].try_into().unwrap()) as usize;
+    let len = slice.len().min(total.saturating_sub(offset));
+    Some(ArtChunk { offset, total, data: &slice[..len] })
+}
+
+// Offset of the chunk probe number `probe` carries, cycling through the art so lost chunks come round again
+pub fn chunk_offset(probe: u64, total: usize, chunk_size: usize) -> usize {
+    let chunks = total.div_ceil(chunk_size).max(1);
+    (probe % chunks as u64) as usize * chunk_size
+}
+
+// Art put back together from the chunks carried by replies
+pub struct ArtReassembly {
+    pub chunk_size: usize,
+    // Length of the art being received, once a chunk has said
+    pub total: Option<usize>,
+    chunks: BTreeMap<usize, Vec<u8>>,
+    pub rendered: bool,
+}
+
+impl ArtReassembly {
+    pub fn new(chunk_size: usize) -> Self {
+        ArtReassembly { chunk_size, total: None, chunks: BTreeMap::new(), rendered: false }
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        self.total.map_or(0, |total| total.div_ceil(self.chunk_size))
+    }
+
+    // File a chunk by its index, returning the index, or None if it can't be placed: past the end,
+    // off a chunk boundary or cut short. A different length means different art, so start over.
+    pub fn add(&mut self, chunk: &ArtChunk) -> Option<usize> {
+        if chunk.offset >= chunk.total || !chunk.offset.is_multiple_of(self.chunk_size)
+            || chunk.data.len() != self.chunk_size.min(chunk.total - chunk.offset) {
+            return None;
+        }
+        if self.total != Some(chunk.total) {
+            self.total = Some(chunk.total);
+            self.chunks.clear();
+            self.rendered = false;
+        }
+        let index = chunk.offset / self.chunk_size;
+        self.chunks.entry(index).or_insert_with(|| chunk.data.to_vec());
+        Some(index)
+    }
+
+    // The whole art once every chunk is in
+    pub fn complete(&self) -> Option<Vec<u8>> {
+        if self.total.is_none() || self.chunks.len() < self.chunk_count() {
+            return None;
+        }
+        Some(self.chunks.values().flatten().copied().collect())
+    }
+
+    // Indexes of the chunks still missing
+    pub fn missing(&self) -> Vec<usize> {
+        (0..self.chunk_count()).filter(|index| !self.chunks.contains_key(index)).collect()
+    }
+}
+
+// Describe chunk indexes as ranges, like "3, 5-7"
+pub fn describe_ranges(indexes: &[usize]) -> String {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for &index in indexes {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == index => *end = index,
+            _ => ranges.push((index, index)),
+        }
+    }
+    ranges.iter()
+        .map(|&(start, end)| if start == end { start.to_string() } else { format!("{}-{}", start, end) })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// Where art is looked for when neither --art nor BINGPING_ART names any: a file, or a directory
+// of them, in the user's config directory
+fn config_art_path() -> Option<PathBuf> {
+    let config_dir = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    let path = config_dir.join("bingping").join("art");
+    path.exists().then_some(path)
+}
+
+// Pick a file from a directory of art, by file name or name without the extension, or at random
+fn pick_art(dir: &Path, name: Option<&str>) -> Result<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .with_context(|| format!("Failed to read the art directory {}", dir.display()))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file() && !path.file_name().is_some_and(|name| name.to_string_lossy().starts_with('.')))
+        .collect();
+    files.sort();
+
+    match name {
+        Some(name) => {
+            let name = OsStr::new(name);
+            files.iter()
+                .find(|path| path.file_name() == Some(name) || path.file_stem() == Some(name))
+                .cloned()
+                .ok_or_else(|| {
+                    let names: Vec<String> = files.iter()
+                        .filter_map(|path| path.file_stem().map(|stem| stem.to_string_lossy().into_owned()))
+                        .collect();
+                    anyhow!("No art named '{}' in {} (found: {})", name.to_string_lossy(), dir.display(), names.join(", "))
+                })
+        },
+        None => files.choose(&mut rand::thread_rng())
+            .cloned()
+            .ok_or_else(|| anyhow!("No art files in {}", dir.display())),
+    }
+}
+
+// The art to show and send: a file, a file from a directory picked by name or at random, or the
+// built-in art when no path is given or configured
+pub fn load_art(path: Option<&Path>, name: Option<&str>) -> Result<String> {
+    let Some(path) = path.map(Path::to_path_buf).or_else(config_art_path) else {
+        if name.is_some() {
+            bail!("--art-name picks from a directory, so --art needs to name one");
+        }
+        return Ok(load_ascii_art());
+    };
+    let file = if path.is_dir() {
+        pick_art(&path, name)?
+    } else if name.is_some() {
+        bail!("--art-name picks from a directory, but {} isn't one", path.display());
+    } else {
+        path
+    };
+
+    let art = fs::read_to_string(&file).with_context(|| format!("Failed to read art from {}", file.display()))?;
+    if art.trim().is_empty() {
+        bail!("{} has no art in it", file.display());
+    }
+    if art.len() > MAX_ART_LEN {
+        bail!("{} is {} bytes, but art can be at most {} bytes", file.display(), art.len(), MAX_ART_LEN);
+    }
+    Ok(art)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ART: &[u8] = b"  /\\_/\\\n ( o.o )\n  > ^ <\n";
+
+    // Echo data after the send time carrying the chunk at `offset`
+    fn chunk_data(art: &[u8], offset: usize, chunk_size: usize) -> Vec<u8> {
+        let mut data = vec![0u8; ART_CHUNK_HEADER_LEN + chunk_size];
+        write_art_chunk(&mut data, art, offset);
+        data
+    }
+
+    fn add(reassembly: &mut ArtReassembly, data: &[u8]) -> Option<usize> {
+        reassembly.add(&parse_art_chunk(data).unwrap())
+    }
+
+    #[test]
+    fn reassembles_out_of_order_and_duplicated_chunks() {
+        let mut reassembly = ArtReassembly::new(8);
+        assert_eq!(ART.len().div_ceil(8), 4);
+        for index in [3, 1, 1, 0, 3, 2] {
+            assert_eq!(add(&mut reassembly, &chunk_data(ART, index * 8, 8)), Some(index));
+        }
+        assert_eq!(reassembly.chunk_count(), 4);
+        assert_eq!(reassembly.complete().as_deref(), Some(ART));
+        assert!(reassembly.missing().is_empty());
+    }
+
+    #[test]
+    fn reports_missing_chunks() {
+        let mut reassembly = ArtReassembly::new(8);
+        assert!(reassembly.complete().is_none());
+        for index in [0, 3] {
+            add(&mut reassembly, &chunk_data(ART, index * 8, 8));
+        }
+        assert!(reassembly.complete().is_none());
+        assert_eq!(reassembly.missing(), [1, 2]);
+        assert_eq!(describe_ranges(&reassembly.missing()), "1-2");
+        assert_eq!(describe_ranges(&[0, 2, 3, 4, 7]), "0, 2-4, 7");
+    }
+
+    #[test]
+    fn rejects_chunks_whose_header_lies() {
+        let mut reassembly = ArtReassembly::new(8);
+        add(&mut reassembly, &chunk_data(ART, 0, 8));
+
+        // Claims the art is shorter than the offset it starts at
+        let mut data = chunk_data(ART, 16, 8);
+        data[8..12].copy_from_slice(&10u32.to_be_bytes());
+        assert_eq!(add(&mut reassembly, &data), None);
+        // Claims more art than it carries
+        let mut data = chunk_data(ART, 8, 8);
+        data[8..12].copy_from_slice(&1000u32.to_be_bytes());
+        assert_eq!(reassembly.add(&parse_art_chunk(&data[..ART_CHUNK_HEADER_LEN + 4]).unwrap()), None);
+        // Starts between chunks
+        assert_eq!(add(&mut reassembly, &chunk_data(ART, 4, 8)), None);
+        assert_eq!(reassembly.total, Some(ART.len()));
+        assert_eq!(reassembly.missing(), [1, 2, 3]);
+
+        // A believable header for a different length is different art
+        let mut data = chunk_data(ART, 8, 8);
+        data[8..12].copy_from_slice(&64u32.to_be_bytes());
+        assert_eq!(add(&mut reassembly, &data), Some(1));
+        assert_eq!(reassembly.total, Some(64));
+        assert_eq!(reassembly.missing(), [0, 2, 3, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn pads_the_last_chunk_and_ignores_other_data() {
+        let data = chunk_data(ART, 24, 8);
+        assert_eq!(&data[ART_CHUNK_HEADER_LEN + 3..], b"#####");
+        let chunk = parse_art_chunk(&data).unwrap();
+        assert_eq!((chunk.offset, chunk.total, chunk.data), (24, ART.len(), &ART[24..]));
+        assert!(parse_art_chunk(&ART[..ART_CHUNK_HEADER_LEN + 4]).is_none());
+        assert!(parse_art_chunk(b"bArt").is_none());
+    }
+}
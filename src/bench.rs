@@ -0,0 +1,200 @@
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, bail};
+use pnet::packet::icmp::{IcmpPacket, IcmpTypes};
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::Packet;
+use rand::random;
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::config::PingConfig;
+use crate::packet::{create_icmp_packet, restamp_echo_packet, split_echo_payload, ECHO_TIMESTAMP_LEN, ICMP_HEADER_LEN};
+use crate::ping::{install_ctrlc_handler, print_statistics};
+use crate::socket::{apply_source, attach_icmp_filter, enable_drop_count, monotonic_ns, recv_batch, send_batch, set_dont_route,
+                    set_mtu_discover, set_tos, wait_readable};
+use crate::stats::PingStats;
+
+// Probes sent when no count is given
+const DEFAULT_PROBES: u16 = 10000;
+
+// Room for the largest IPv4 header in front of each reply
+const MAX_IPV4_HEADER_LEN: usize = 60;
+
+// How long to back off when the send queue is full
+const SEND_BACKOFF: Duration = Duration::from_millis(1);
+
+// Replies counted so far, and what the kernel says it dropped
+struct BenchReceiver {
+    identifier: u16,
+    received: Vec<bool>,
+    stats: PingStats,
+    drops: Option<u32>,
+    recv_calls: u64,
+    last_reply: Option<Instant>,
+}
+
+impl BenchReceiver {
+    // Read every reply waiting on the socket, a batch per recvmmsg call
+    fn drain(&mut self, fd: RawFd, buffers: &mut [Vec<u8>]) -> io::Result<()> {
+        loop {
+            let batch = recv_batch(fd, buffers)?;
+            if batch.datagrams.is_empty() {
+                return Ok(());
+            }
+            let recv_time = monotonic_ns();
+            self.recv_calls += 1;
+            self.drops = batch.drops.or(self.drops);
+            for (buffer, &(len, _)) in buffers.iter().zip(&batch.datagrams) {
+                self.handle(&buffer[..len.min(buffer.len())], recv_time);
+            }
+        }
+    }
+
+    // Count an echo reply to one of our probes
+    fn handle(&mut self, datagram: &[u8], recv_time: u64) {
+        let Some(ip_packet) = Ipv4Packet::new(datagram) else {
+            return;
+        };
+        let Some(packet) = datagram.get(ip_packet.get_header_length() as usize * 4..).and_then(IcmpPacket::new) else {
+            return;
+        };
+        let payload = packet.payload();
+        if packet.get_icmp_type() != IcmpTypes::EchoReply || payload.len() < 4 {
+            return;
+        }
+        let reply_id = u16::from_be_bytes([payload[0], payload[1]]);
+        let reply_seq = u16::from_be_bytes([payload[2], payload[3]]) as usize;
+        let (Some(sent), _) = split_echo_payload(payload) else {
+            return;
+        };
+        if reply_id != self.identifier || reply_seq >= self.received.len() || sent > recv_time {
+            return;
+        }
+
+        if self.received[reply_seq] {
+            self.stats.duplicates += 1;
+        } else {
+            self.received[reply_seq] = true;
+            self.stats.update((recv_time - sent) as f64 / 1_000_000.0);
+        }
+        self.last_reply = Some(Instant::now());
+    }
+}
+
+// Send echo requests as fast as the socket takes them, batching sends with sendmmsg and receives
+// with recvmmsg, then report the rates achieved and any receive-side drops
+pub fn benchmark(config: &PingConfig, batch: usize) -> Result<()> {
+    if !config.ip_addr.is_ipv4() {
+        bail!("Benchmark mode is only supported for IPv4 destinations");
+    }
+    let total = config.count.unwrap_or(DEFAULT_PROBES) as usize;
+    let size = config.packet_size;
+    if size < ECHO_TIMESTAMP_LEN {
+        bail!("Benchmark probes need at least {} bytes of data to carry their send time", ECHO_TIMESTAMP_LEN);
+    }
+    let identifier = random::<u16>();
+
+    let socket = Socket::new(Domain::IPV4, Type::from(libc::SOCK_RAW), Some(Protocol::ICMPV4))
+        .context("Failed to create ICMP socket")?;
+    let fd = socket.as_raw_fd();
+    apply_source(fd, config.source.as_ref(), config.mark)
+        .context("Failed to bind to the source interface or set the firewall mark")?;
+    if let Some(mode) = config.pmtu_discovery {
+        set_mtu_discover(fd, mode).context("Failed to set path MTU discovery mode")?;
+    }
+    if config.bypass_route {
+        set_dont_route(fd).context("Failed to bypass routing")?;
+    }
+    if let Some(tos) = config.tos {
+        set_tos(fd, config.ip_addr, tos).context("Failed to set TOS")?;
+    }
+    socket.set_ttl(config.ttl as u32).context("Failed to set TTL")?;
+    socket.set_nonblocking(true).context("Failed to make the ICMP socket non-blocking")?;
+    let drops_reported = enable_drop_count(fd).is_ok();
+
+    // Only our replies should reach the receive queue, so its drops are ours alone
+    if let Err(e) = attach_icmp_filter(fd, identifier, &[IcmpTypes::EchoReply.0], &[]) {
+        eprintln!("Failed to attach BPF filter, filtering replies in userspace: {}", e);
+    }
+
+    let shutdown = install_ctrlc_handler()?;
+
+    // One probe is built up front; each copy gets its own sequence number and send time
+    let packet_len = size + ICMP_HEADER_LEN;
+    let mut template = vec![0u8; packet_len];
+    create_icmp_packet(&mut template, 0, identifier, size, config.art.as_bytes());
+    let mut packets = vec![template; batch];
+    let mut recv_buffers = vec![vec![0u8; packet_len + MAX_IPV4_HEADER_LEN]; batch];
+
+    let mut receiver = BenchReceiver {
+        identifier,
+        received: vec![false; total],
+        stats: PingStats::new(),
+        drops: None,
+        recv_calls: 0,
+        last_reply: None,
+    };
+
+    println!("BENCHMARK {} ({}) {} probes of {} bytes, {} per batch.",
+             config.destination, config.ip_addr, total, size, batch);
+
+    let start_time = Instant::now();
+    let mut sent = 0;
+    let mut send_calls = 0u64;
+    let mut send_backoffs = 0u64;
+
+    while sent < total && shutdown.is_running() {
+        let count = batch.min(total - sent);
+        for (i, packet) in packets[..count].iter_mut().enumerate() {
+            restamp_echo_packet(packet, (sent + i) as u16, monotonic_ns());
+        }
+        let slices: Vec<&[u8]> = packets[..count].iter().map(Vec::as_slice).collect();
+
+        match send_batch(fd, &slices, config.ip_addr) {
+            Ok(went_out) => {
+                sent += went_out;
+                send_calls += 1;
+            },
+            // The send queue is full, let it drain while we read replies
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.raw_os_error() == Some(libc::ENOBUFS) => {
+                send_backoffs += 1;
+                wait_readable(&[fd, shutdown.fd()], Some(SEND_BACKOFF))?;
+            },
+            Err(e) => return Err(e).context("Failed to send probes"),
+        }
+
+        receiver.drain(fd, &mut recv_buffers).context("Failed to receive replies")?;
+    }
+    let send_elapsed = start_time.elapsed();
+
+    // Give outstanding replies until the timeout to arrive
+    let deadline = Instant::now() + Duration::from_millis(config.timeout_ms);
+    while shutdown.is_running() && (receiver.stats.packets_received as usize) < sent {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        wait_readable(&[fd, shutdown.fd()], Some(deadline - now))?;
+        receiver.drain(fd, &mut recv_buffers).context("Failed to receive replies")?;
+    }
+
+    receiver.stats.packets_sent = sent as u64;
+    print_statistics(&config.destination, &receiver.stats, start_time.elapsed());
+
+    let rate = |packets: f64, elapsed: Duration| packets / elapsed.as_secs_f64().max(f64::EPSILON);
+    let recv_elapsed = receiver.last_reply.map_or(Duration::ZERO, |last| last - start_time);
+    println!("sent {:.0} pps in {} sendmmsg calls ({:.1} per call, {} backoffs)",
+             rate(sent as f64, send_elapsed), send_calls,
+             sent as f64 / send_calls.max(1) as f64, send_backoffs);
+    println!("received {:.0} pps in {} recvmmsg calls ({:.1} per call)",
+             rate(receiver.stats.packets_received as f64, recv_elapsed), receiver.recv_calls,
+             receiver.stats.packets_received as f64 / receiver.recv_calls.max(1) as f64);
+    match (drops_reported, receiver.drops) {
+        (false, _) => println!("receive drops: not available (SO_RXQ_OVFL unsupported)"),
+        (true, drops) => println!("receive drops: {} (socket buffer overflows)", drops.unwrap_or(0)),
+    }
+
+    Ok(())
+}
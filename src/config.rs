@@ -1,70 +1,318 @@
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+#[cfg(target_os = "linux")]
+use std::time::Duration;
 use anyhow::{Context, Result, anyhow};
 use dns_lookup::lookup_host;
 use clap::Parser;
 
-use crate::args::Args;
+use crate::args::{Args, Command};
+use crate::art::{load_art, MIN_CHUNKED_SIZE};
+use crate::dns::{parse_server, DnsQuery, DnsType};
+use crate::http::{HttpMethod, HttpTarget};
+use crate::image_art::image_to_art;
+#[cfg(target_os = "linux")]
+use crate::netns::enter_netns;
+use crate::packet::IpOption;
+#[cfg(target_os = "linux")]
+use crate::serve::ServeOptions;
+use crate::simulate::SimulationSpec;
+#[cfg(target_os = "windows")]
+use crate::packet::IpTimestampMode;
+#[cfg(target_os = "linux")]
+use crate::socket::{PmtuDiscovery, SourceBinding};
+
+// Stand-ins on platforms without the socket options behind them, so these settings are always None
+#[cfg(not(target_os = "linux"))]
+pub enum PmtuDiscovery {}
+#[cfg(not(target_os = "linux"))]
+pub enum SourceBinding {}
+#[cfg(not(target_os = "linux"))]
+pub enum ServeOptions {}
+
+// What kind of probe to send
+pub enum PingMode {
+    Icmp,
+    IcmpTimestamp,
+    Http(HttpTarget),
+    Dns(DnsQuery),
+    Pmtu,
+    // Summarise the ping runs in a capture file instead of sending anything
+    Analyze(PathBuf),
+    // Answer other hosts' echo requests instead of sending any
+    Serve(ServeOptions),
+    // Batched echo requests sent as fast as possible, `batch` per system call
+    Benchmark { batch: usize },
+}
 
 // Configuration for the ping operation
 pub struct PingConfig {
+    pub mode: PingMode,
     pub destination: String,
     pub ip_addr: IpAddr,
     pub count: Option<u16>,
     pub packet_size: usize,
+    pub packet_sizes: Vec<usize>,
     pub interval_ms: u64,
     pub timeout_ms: u64,
     pub ttl: u8,
     pub quiet: bool,
     pub rainbow: bool,
+    pub pmtu_discovery: Option<PmtuDiscovery>,
+    pub ip_option: Option<IpOption>,
+    pub tos: Option<u8>,
+    pub source: Option<SourceBinding>,
+    pub mark: Option<u32>,
+    pub bypass_route: bool,
+    pub broadcast: bool,
+    // Ping over a simulated network instead of raw sockets
+    pub simulate: Option<SimulationSpec>,
+    // Capture probes and replies to this pcap file
+    pub pcap: Option<PathBuf>,
+    // Send the art a slice per probe, with an offset header, and reassemble it from the replies
+    pub chunked_art: bool,
+    // The art shown in the banner and carried by echo requests
+    pub art: String,
+}
+
+// Probes per sendmmsg/recvmmsg call when benchmarking
+#[cfg(target_os = "linux")]
+const DEFAULT_BATCH: u16 = 64;
+
+// Resolve a hostname, preferring an IPv4 address
+fn resolve_host(host: &str) -> Result<IpAddr> {
+    let host_addresses = lookup_host(host)
+        .with_context(|| format!("Failed to resolve hostname: {}", host))?;
+    
+    // First try to find an IPv4 address, then fall back to any IP address
+    host_addresses.iter()
+        .find(|ip| ip.is_ipv4())
+        .copied()
+        .or_else(|| host_addresses.first().copied())
+        .ok_or_else(|| anyhow!("No IP addresses found for host: {}", host))
+}
+
+// Parse a packet size, or a `start:end:step` range of sizes to cycle through
+fn parse_size_spec(spec: &str) -> Result<Vec<usize>> {
+    let parse = |value: &str| value.trim().parse::<usize>()
+        .with_context(|| format!("Invalid packet size: {}", value));
+    
+    let parts: Vec<&str> = spec.split(':').collect();
+    let sizes = match parts.as_slice() {
+        [size] => vec![parse(size)?],
+        [start, end] | [start, end, _] => {
+            let (start, end) = (parse(start)?, parse(end)?);
+            let step = match parts.get(2) {
+                Some(step) => parse(step)?,
+                None => 1,
+            };
+            if step == 0 || start > end {
+                return Err(anyhow!("Invalid packet size range: {}", spec));
+            }
+            (start..=end).step_by(step).collect()
+        },
+        _ => return Err(anyhow!("Invalid packet size range: {}", spec)),
+    };
+    
+    if let Some(size) = sizes.iter().find(|&&size| size > 65507) {
+        return Err(anyhow!("Packet size too large: {} (maximum 65507)", size));
+    }
+    Ok(sizes)
+}
+
+// Settings for `bingping serve`
+#[cfg(target_os = "linux")]
+fn serve_options(udp_port: Option<u16>, delay_ms: u64, loss: Option<f64>) -> Result<ServeOptions> {
+    Ok(ServeOptions {
+        udp_port,
+        delay: Duration::from_millis(delay_ms),
+        loss: loss.unwrap_or(0.0),
+    })
+}
+
+// Serving answers on raw sockets the Linux way
+#[cfg(not(target_os = "linux"))]
+fn serve_options(_udp_port: Option<u16>, _delay_ms: u64, _loss: Option<f64>) -> Result<ServeOptions> {
+    Err(anyhow!("Serving is only supported on Linux"))
 }
 
 // Parse command line arguments into a unified PingConfig
 pub fn parse_args() -> Result<PingConfig> {
     let args = Args::parse();
     
-    // Resolve hostname to IP address
-    let host_addresses = lookup_host(&args.destination)
-        .with_context(|| format!("Failed to resolve hostname: {}", args.destination))?;
+    // Enter the network namespace first, so name resolution and every socket happen inside it
+    #[cfg(target_os = "linux")]
+    if let Some(name) = &args.netns {
+        enter_netns(name)?;
+    }
     
-    // First try to find an IPv4 address, then fall back to any IP address
-    let ip_addr = host_addresses.iter()
-        .find(|ip| ip.is_ipv4())
-        .copied()
-        .or_else(|| host_addresses.get(0).copied())
-        .ok_or_else(|| anyhow!("No IP addresses found for host: {}", args.destination))?;
+    // Subcommands carry their own destination
+    let destination = match &args.command {
+        Some(Command::Pmtu { host }) => host.clone(),
+        Some(Command::Analyze { file }) => file.display().to_string(),
+        Some(Command::Serve { .. }) => Ipv4Addr::UNSPECIFIED.to_string(),
+        None => args.destination.clone()
+            .ok_or_else(|| anyhow!("A destination is required"))?,
+    };
+    
+    // Batch size when benchmarking, which only Linux supports
+    #[cfg(target_os = "linux")]
+    let benchmark = args.benchmark.then(|| args.batch.unwrap_or(DEFAULT_BATCH) as usize);
+    #[cfg(target_os = "windows")]
+    let benchmark: Option<usize> = None;
+    
+    // The simulated network is only built on Linux too
+    #[cfg(target_os = "linux")]
+    let simulate = args.simulate.clone();
+    #[cfg(target_os = "windows")]
+    let simulate: Option<SimulationSpec> = None;
+    
+    // Packet capture is Linux-only as well
+    #[cfg(target_os = "linux")]
+    let pcap = args.pcap.clone();
+    #[cfg(target_os = "windows")]
+    let pcap: Option<PathBuf> = None;
+    
+    // So is sending the art in chunks
+    #[cfg(target_os = "linux")]
+    let chunked_art = args.chunked_art;
+    #[cfg(target_os = "windows")]
+    let chunked_art = false;
+    
+    // Subcommands pick their own mode, DNS mode queries the destination name,
+    // URLs are probed over HTTP, anything else is an ICMP destination
+    let mode = if let Some(Command::Pmtu { .. }) = &args.command {
+        PingMode::Pmtu
+    } else if let Some(Command::Analyze { file }) = &args.command {
+        PingMode::Analyze(file.clone())
+    } else if let Some(Command::Serve { udp, delay, loss }) = &args.command {
+        PingMode::Serve(serve_options(*udp, *delay, *loss)?)
+    } else if let Some(server) = &args.dns {
+        PingMode::Dns(DnsQuery {
+            server: parse_server(server)?,
+            name: destination.clone(),
+            qtype: args.dns_type.unwrap_or(DnsType::A),
+        })
+    } else if destination.starts_with("http://") || destination.starts_with("https://") {
+        let method = args.http_method.unwrap_or(HttpMethod::Head);
+        PingMode::Http(HttpTarget::parse(&destination, method)?)
+    } else if args.icmp_timestamp {
+        PingMode::IcmpTimestamp
+    } else if let Some(batch) = benchmark {
+        PingMode::Benchmark { batch }
+    } else {
+        PingMode::Icmp
+    };
+    
+    // Resolve hostname to IP address
+    let ip_addr = match &mode {
+        PingMode::Dns(query) => query.server.ip(),
+        PingMode::Http(target) => resolve_host(&target.host)?,
+        PingMode::Analyze(_) | PingMode::Serve(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        PingMode::Icmp | PingMode::IcmpTimestamp | PingMode::Pmtu | PingMode::Benchmark { .. } => {
+            resolve_host(&destination)?
+        },
+    };
     
     // Get configuration values, using defaults if not specified
     #[cfg(target_os = "linux")]
-    let (count, packet_size, interval_ms, timeout_ms, ttl, quiet, rainbow) = (
+    let (count, packet_sizes, interval_ms, timeout_ms, ttl, quiet, rainbow, pmtu_discovery, ip_option, tos, source, mark,
+         bypass_route, broadcast) = (
         args.count,
-        args.size.map(|s| s as usize).unwrap_or(4096),
+        args.size.as_deref().map(parse_size_spec).transpose()?.unwrap_or_else(|| vec![4096]),
         (args.interval.unwrap_or(1.0) * 1000.0) as u64,
         (args.timeout.unwrap_or(4.0) * 1000.0) as u64,
         args.ttl.unwrap_or(64),
         args.quiet,
         args.rainbow,
+        args.pmtu_discovery,
+        if args.record_route {
+            Some(IpOption::RecordRoute)
+        } else {
+            args.ip_timestamp.map(IpOption::Timestamp)
+        },
+        // DSCP fills the upper six bits of the TOS byte, ECN the lower two
+        args.tos.or_else(|| match (args.dscp, args.ecn) {
+            (None, None) => None,
+            (dscp, ecn) => Some(dscp.unwrap_or(0) << 2 | ecn.map(|ecn| ecn.bits()).unwrap_or(0)),
+        }),
+        args.interface.as_deref().map(SourceBinding::parse),
+        args.mark,
+        args.bypass_route,
+        args.broadcast,
     );
     
     #[cfg(target_os = "windows")]
-    let (count, packet_size, interval_ms, timeout_ms, ttl, quiet, rainbow) = (
+    let (count, packet_sizes, interval_ms, timeout_ms, ttl, quiet, rainbow, pmtu_discovery, ip_option, tos, source, mark,
+         bypass_route, broadcast) = (
         args.count,
-        args.size.map(|s| s as usize).unwrap_or(4096),
+        vec![args.size.map(|s| s as usize).unwrap_or(4096)],
         (args.interval.unwrap_or(1.0) * 1000.0) as u64,
         (args.timeout.unwrap_or(4.0) * 1000.0) as u64,
         args.ttl.unwrap_or(128),
         false,
         args.rainbow,
+        None,
+        if args.record_route {
+            Some(IpOption::RecordRoute)
+        } else if args.timestamp {
+            Some(IpOption::Timestamp(IpTimestampMode::Tsandaddr))
+        } else {
+            None
+        },
+        None,
+        None,
+        None,
+        false,
+        false,
     );
     
+    // Custom art is read now, so a bad path fails before anything is sent
+    let art = match &args.art_image {
+        Some(path) => image_to_art(path, args.art_width, args.art_color)?,
+        None => load_art(args.art.as_deref(), args.art_name.as_deref())?,
+    };
+    
+    // The first size doubles as the size for everything that doesn't sweep
+    let packet_size = packet_sizes[0];
+    
+    // Every chunk has to be the same size, with room for the chunk header and some art
+    if chunked_art {
+        if packet_sizes.len() > 1 {
+            return Err(anyhow!("--chunked-art needs a single packet size, not a range"));
+        }
+        if packet_size < MIN_CHUNKED_SIZE {
+            return Err(anyhow!("--chunked-art needs packets of at least {} bytes", MIN_CHUNKED_SIZE));
+        }
+    }
+    
+    // Benchmark probes are copies of a single template
+    if matches!(mode, PingMode::Benchmark { .. }) && packet_sizes.len() > 1 {
+        return Err(anyhow!("--benchmark needs a single packet size, not a range"));
+    }
+    
     Ok(PingConfig {
-        destination: args.destination,
+        mode,
+        destination,
         ip_addr,
         count,
         packet_size,
+        packet_sizes,
         interval_ms,
         timeout_ms,
         ttl,
         quiet,
         rainbow,
+        pmtu_discovery,
+        ip_option,
+        tos,
+        source,
+        mark,
+        bypass_route,
+        broadcast,
+        simulate,
+        pcap,
+        chunked_art,
+        art,
     })
 } 
\ No newline at end of file
@@ -1,30 +1,84 @@
+use std::env;
+use std::io::{self, IsTerminal};
+use std::sync::OnceLock;
+
+use terminal_size::{terminal_size_of, Width};
+use unicode_width::UnicodeWidthChar;
+
 // Load ASCII art from file
 pub fn load_ascii_art() -> String {
     include_str!("../ascii-art.txt").to_string()
 }
 
+// What colours stdout can show
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorSupport {
+    None,
+    Ansi256,
+    Truecolor,
+}
+
+// Decide once from the environment: NO_COLOR turns colour off, CLICOLOR_FORCE turns it on even
+// when stdout isn't a terminal, and COLORTERM says whether 24-bit colour works
+pub fn color_support() -> ColorSupport {
+    static SUPPORT: OnceLock<ColorSupport> = OnceLock::new();
+    *SUPPORT.get_or_init(|| {
+        let set = |name: &str| env::var_os(name).is_some_and(|value| !value.is_empty());
+        if set("NO_COLOR") {
+            return ColorSupport::None;
+        }
+        let forced = env::var("CLICOLOR_FORCE").is_ok_and(|value| !value.is_empty() && value != "0");
+        let dumb = env::var("TERM").is_ok_and(|term| term == "dumb");
+        if !forced && (!io::stdout().is_terminal() || dumb) {
+            return ColorSupport::None;
+        }
+        match env::var("COLORTERM").as_deref() {
+            Ok("truecolor") | Ok("24bit") => ColorSupport::Truecolor,
+            _ => ColorSupport::Ansi256,
+        }
+    })
+}
+
+// Nearest colour in the 6x6x6 cube of the 256-colour palette
+pub fn rgb_to_ansi256([r, g, b]: [u8; 3]) -> u8 {
+    let level = |channel: u8| (channel as u16 * 5 + 127) / 255;
+    (16 + 36 * level(r) + 6 * level(g) + level(b)) as u8
+}
+
+// Escape setting the text colour, given as an entry in the 256-colour palette and the same colour in RGB
+fn foreground(index: u8, rgb: [u8; 3], support: ColorSupport) -> String {
+    match support {
+        ColorSupport::None => String::new(),
+        ColorSupport::Ansi256 => format!("\x1b[38;5;{}m", index),
+        ColorSupport::Truecolor => format!("\x1b[38;2;{};{};{}m", rgb[0], rgb[1], rgb[2]),
+    }
+}
+
+fn reset(support: ColorSupport) -> &'static str {
+    if support == ColorSupport::None { "" } else { "\x1b[0m" }
+}
+
 // Helper function to format text with rainbow colors
-pub fn rainbow_text(text: &str) -> String {
+pub fn rainbow_text(text: &str, support: ColorSupport) -> String {
     let colors = [
-        "\x1b[38;5;196m", // Red
-        "\x1b[38;5;202m", // Orange
-        "\x1b[38;5;226m", // Yellow
-        "\x1b[38;5;46m",  // Green
-        "\x1b[38;5;21m",  // Blue
-        "\x1b[38;5;93m",  // Indigo
-        "\x1b[38;5;163m", // Violet
+        (196, [255, 0, 0]),   // Red
+        (202, [255, 95, 0]),  // Orange
+        (226, [255, 255, 0]), // Yellow
+        (46, [0, 255, 0]),    // Green
+        (21, [0, 0, 255]),    // Blue
+        (93, [135, 0, 255]),  // Indigo
+        (163, [215, 0, 175]), // Violet
     ];
     
-    let reset = "\x1b[0m";
     let mut result = String::new();
     let lines: Vec<&str> = text.lines().collect();
     
     for (i, line) in lines.iter().enumerate() {
         // Get the color index based on line number
-        let color_idx = (i / 2) % colors.len();
-        result.push_str(colors[color_idx]);
+        let (index, rgb) = colors[(i / 2) % colors.len()];
+        result.push_str(&foreground(index, rgb, support));
         result.push_str(line);
-        result.push_str(reset);
+        result.push_str(reset(support));
         
         // Add newline if this isn't the last line
         if i < lines.len() - 1 {
@@ -36,6 +90,201 @@ pub fn rainbow_text(text: &str) -> String {
 }
 
 // Format text with pink color
-pub fn pink_text(text: &str) -> String {
-    format!("\x1b[38;5;213m{}\x1b[0m", text)
-} 
\ No newline at end of file
+pub fn pink_text(text: &str, support: ColorSupport) -> String {
+    format!("{}{}{}", foreground(213, [255, 135, 255], support), text, reset(support))
+}
+
+// Rewrite the colour escapes art brings with it for what the terminal supports: dropped without
+// colour, and 24-bit colours turned into their nearest palette entry with only 256
+fn adapt_colors(art: &str, support: ColorSupport) -> String {
+    let mut result = String::new();
+    let mut text = art;
+    while let Some(c) = text.chars().next() {
+        let Some(sgr) = sgr_prefix(text) else {
+            result.push(c);
+            text = &text[c.len_utf8()..];
+            continue;
+        };
+        text = &text[sgr.len()..];
+        match support {
+            ColorSupport::None => {},
+            ColorSupport::Truecolor => result.push_str(sgr),
+            ColorSupport::Ansi256 => {
+                let params: Vec<u16> = sgr[2..sgr.len() - 1].split(';').filter_map(|p| p.parse().ok()).collect();
+                let mut converted = Vec::new();
+                let mut i = 0;
+                while i < params.len() {
+                    match params[i..] {
+                        [kind @ (38 | 48), 2, r, g, b, ..] => {
+                            let index = rgb_to_ansi256([r as u8, g as u8, b as u8]);
+                            converted.push(format!("{};5;{}", kind, index));
+                            i += 5;
+                        },
+                        _ => {
+                            converted.push(params[i].to_string());
+                            i += 1;
+                        },
+                    }
+                }
+                result.push_str(&format!("\x1b[{}m", converted.join(";")));
+            },
+        }
+    }
+    result
+}
+
+// Colour art for printing, fitted to the terminal: art converted from an image keeps its own
+// colours, anything else is rainbow or pink
+pub fn style_art(art: &str, rainbow: bool) -> String {
+    let support = color_support();
+    let art = match terminal_width() {
+        Some(width) => fit_width(art, width),
+        None => art.to_string(),
+    };
+    if art.contains("\x1b[") {
+        format!("{}{}", adapt_colors(&art, support), reset(support))
+    } else if rainbow {
+        rainbow_text(&art, support)
+    } else {
+        pink_text(&art, support)
+    }
+}
+
+// A colour escape like ESC[38;5;213m at the start of `text`
+pub fn sgr_prefix(text: &str) -> Option<&str> {
+    let params = text.strip_prefix("\x1b[")?;
+    let len = params.bytes().take_while(|&c| c.is_ascii_digit() || c == b';').count();
+    (params.as_bytes().get(len) == Some(&b'm')).then(|| &text[..len + 3])
+}
+
+// Add printable characters, newlines and whole colour escapes from `text` to the art
+fn push_printable(art: &mut String, mut text: &str) {
+    while let Some(c) = text.chars().next() {
+        if let Some(sgr) = sgr_prefix(text) {
+            art.push_str(sgr);
+            text = &text[sgr.len()..];
+            continue;
+        }
+        if !c.is_control() || c == '\n' {
+            art.push(c);
+        }
+        text = &text[c.len_utf8()..];
+    }
+}
+
+// Art carried by a reply, keeping printable characters and colour escapes but nothing else a
+// terminal would act on. Bytes that aren't UTF-8 show as replacement characters, except a
+// character cut off at the end of the payload.
+pub fn sanitize_art(bytes: &[u8]) -> String {
+    let mut art = String::new();
+    let mut chunks = bytes.utf8_chunks().peekable();
+    while let Some(chunk) = chunks.next() {
+        push_printable(&mut art, chunk.valid());
+        if !chunk.invalid().is_empty() && chunks.peek().is_some() {
+            art.push(char::REPLACEMENT_CHARACTER);
+        }
+    }
+    art
+}
+
+// Columns a line takes up on a terminal, counting wide characters twice and colour escapes not at all
+pub fn display_width(line: &str) -> usize {
+    let mut width = 0;
+    let mut text = line;
+    while let Some(c) = text.chars().next() {
+        if let Some(sgr) = sgr_prefix(text) {
+            text = &text[sgr.len()..];
+            continue;
+        }
+        width += c.width().unwrap_or(0);
+        text = &text[c.len_utf8()..];
+    }
+    width
+}
+
+// Width of art in columns: that of its widest line
+pub fn art_width(art: &str) -> usize {
+    art.lines().map(display_width).max().unwrap_or(0)
+}
+
+// Columns available on stdout's terminal, or in COLUMNS when it isn't one. None for a file or pipe,
+// where lines can be as long as they like.
+fn terminal_width() -> Option<usize> {
+    if let Some((Width(width), _)) = terminal_size_of(io::stdout()) {
+        return Some(width as usize);
+    }
+    env::var("COLUMNS").ok()?.parse().ok().filter(|&width| width > 0)
+}
+
+// One character of art and the colour escape in force for it
+struct Cell<'a> {
+    color: Option<&'a str>,
+    c: char,
+}
+
+// Split a line into cells, along with the escapes after the last character
+fn cells(line: &str) -> (Vec<Cell<'_>>, String) {
+    let mut cells = Vec::new();
+    let mut color = None;
+    let mut trailing = String::new();
+    let mut text = line;
+    while let Some(c) = text.chars().next() {
+        if let Some(sgr) = sgr_prefix(text) {
+            color = Some(sgr);
+            trailing.push_str(sgr);
+            text = &text[sgr.len()..];
+            continue;
+        }
+        cells.push(Cell { color, c });
+        trailing.clear();
+        text = &text[c.len_utf8()..];
+    }
+    (cells, trailing)
+}
+
+// Shrink art wider than `width` columns by sampling rows and columns evenly, so it keeps its
+// shape instead of wrapping. Narrow art is left alone.
+pub fn fit_width(art: &str, width: usize) -> String {
+    let columns = art_width(art);
+    if columns <= width || width == 0 {
+        return art.to_string();
+    }
+    let scale = width as f64 / columns as f64;
+    let lines: Vec<&str> = art.lines().collect();
+    let rows = ((lines.len() as f64 * scale).round() as usize).max(1);
+
+    let mut result = String::new();
+    for row in 0..rows {
+        let line = lines[((row as f64 / scale) as usize).min(lines.len() - 1)];
+        let (cells, trailing) = cells(line);
+        // Which cell covers each column, so wide characters are sampled by the space they take
+        let mut column_cells = Vec::new();
+        for (index, cell) in cells.iter().enumerate() {
+            for _ in 0..cell.c.width().unwrap_or(0) {
+                column_cells.push(index);
+            }
+        }
+
+        let (mut used, mut last, mut color) = (0, None, None);
+        for column in 0..width {
+            let Some(&index) = column_cells.get((column as f64 / scale) as usize) else {
+                break;
+            };
+            let cell = &cells[index];
+            let cell_width = cell.c.width().unwrap_or(0);
+            if last == Some(index) || used + cell_width > width {
+                continue;
+            }
+            if cell.color != color {
+                result.push_str(cell.color.unwrap_or("\x1b[0m"));
+                color = cell.color;
+            }
+            result.push(cell.c);
+            used += cell_width;
+            last = Some(index);
+        }
+        result.push_str(&trailing);
+        result.push('\n');
+    }
+    result
+}
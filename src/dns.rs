@@ -0,0 +1,291 @@
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, anyhow, bail};
+use clap::ValueEnum;
+use rand::random;
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::config::PingConfig;
+use crate::ping::run_probe_loop;
+#[cfg(target_os = "linux")]
+use crate::socket::apply_source;
+
+// Record type requested by each DNS probe
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+#[clap(rename_all = "UPPER")]
+pub enum DnsType {
+    A,
+    Ns,
+    Cname,
+    Soa,
+    Ptr,
+    Mx,
+    Txt,
+    Aaaa,
+    Srv,
+    Any,
+}
+
+impl DnsType {
+    fn code(&self) -> u16 {
+        match self {
+            DnsType::A => 1,
+            DnsType::Ns => 2,
+            DnsType::Cname => 5,
+            DnsType::Soa => 6,
+            DnsType::Ptr => 12,
+            DnsType::Mx => 15,
+            DnsType::Txt => 16,
+            DnsType::Aaaa => 28,
+            DnsType::Srv => 33,
+            DnsType::Any => 255,
+        }
+    }
+}
+
+// A DNS query sent repeatedly to a single resolver
+#[derive(Clone, Debug)]
+pub struct DnsQuery {
+    pub server: SocketAddr,
+    pub name: String,
+    pub qtype: DnsType,
+}
+
+// Parse a resolver given as `addr`, `addr:port` or `[v6addr]:port`, defaulting to port 53
+pub fn parse_server(server: &str) -> Result<SocketAddr> {
+    if let Ok(addr) = server.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    if let Ok(ip) = server.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, 53));
+    }
+
+    // Fall back to resolving a hostname, with an optional port
+    let (host, port) = match server.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse::<u16>()
+            .with_context(|| format!("Invalid DNS server port: {}", server))?),
+        None => (server, 53),
+    };
+    let addresses = dns_lookup::lookup_host(host)
+        .with_context(|| format!("Failed to resolve DNS server: {}", host))?;
+    addresses.iter()
+        .find(|ip| ip.is_ipv4())
+        .or_else(|| addresses.first())
+        .map(|ip| SocketAddr::new(*ip, port))
+        .ok_or_else(|| anyhow!("No IP addresses found for DNS server: {}", host))
+}
+
+// Build a standard recursive query for a single question
+pub fn build_query(id: u16, name: &str, qtype: DnsType) -> Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(12 + name.len() + 6);
+
+    // Header: ID, flags (RD set), QDCOUNT=1, ANCOUNT/NSCOUNT/ARCOUNT=0
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&0x0100u16.to_be_bytes());
+    query.extend_from_slice(&1u16.to_be_bytes());
+    query.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+
+    // QNAME as a sequence of length-prefixed labels
+    for label in name.trim_end_matches('.').split('.').filter(|l| !l.is_empty()) {
+        if label.len() > 63 {
+            bail!("DNS label too long: {}", label);
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+
+    // QTYPE and QCLASS (IN)
+    query.extend_from_slice(&qtype.code().to_be_bytes());
+    query.extend_from_slice(&1u16.to_be_bytes());
+
+    Ok(query)
+}
+
+// Fields of a DNS response header we report on
+#[derive(Debug)]
+pub struct DnsResponse {
+    pub id: u16,
+    pub rcode: u8,
+    pub answers: u16,
+    pub truncated: bool,
+}
+
+// Parse the header of a DNS response
+pub fn parse_response(response: &[u8]) -> Result<DnsResponse> {
+    if response.len() < 12 {
+        bail!("DNS response too short: {} bytes", response.len());
+    }
+    if response[2] & 0x80 == 0 {
+        bail!("DNS message is not a response");
+    }
+    Ok(DnsResponse {
+        id: u16::from_be_bytes([response[0], response[1]]),
+        rcode: response[3] & 0x0F,
+        answers: u16::from_be_bytes([response[6], response[7]]),
+        truncated: response[2] & 0x02 != 0,
+    })
+}
+
+// Human-readable name of a response code
+pub fn rcode_name(rcode: u8) -> &'static str {
+    match rcode {
+        0 => "NOERROR",
+        1 => "FORMERR",
+        2 => "SERVFAIL",
+        3 => "NXDOMAIN",
+        4 => "NOTIMP",
+        5 => "REFUSED",
+        _ => "UNKNOWN",
+    }
+}
+
+// Ping a DNS resolver by repeatedly querying it and timing the responses
+pub fn dns_ping(config: &PingConfig, query: &DnsQuery) -> Result<()> {
+    let timeout = Duration::from_millis(config.timeout_ms);
+
+    // A single socket is reused for every probe, stale answers are told apart by ID
+    let socket = Socket::new(Domain::for_address(query.server), Type::DGRAM, Some(Protocol::UDP))
+        .context("Failed to create UDP socket")?;
+    #[cfg(target_os = "linux")]
+    apply_source(socket.as_raw_fd(), config.source.as_ref(), config.mark)
+        .context("Failed to bind to the source interface or set the firewall mark")?;
+    socket.connect(&query.server.into())
+        .with_context(|| format!("Failed to connect to DNS server {}", query.server))?;
+    let socket = UdpSocket::from(socket);
+
+    // Random base for query IDs, advanced by the sequence number
+    let id_base = random::<u16>();
+
+    let qtype = query.qtype.to_possible_value().map(|v| v.get_name().to_string()).unwrap_or_default();
+    println!("DNS {} {} via {}", query.name, qtype, query.server);
+
+    let mut buffer = [0u8; 4096];
+    run_probe_loop(config, |seq| {
+        let id = id_base.wrapping_add(seq);
+        let request = build_query(id, &query.name, query.qtype)?;
+        let (len, response, rtt) = exchange(&socket, id, &request, timeout, &mut buffer)?;
+        let truncated = if response.truncated { " truncated" } else { "" };
+        println!("{} bytes from {}: seq={} id={} rcode={} answers={}{} time={:.1} ms",
+                 len, query.server, seq, id, rcode_name(response.rcode), response.answers, truncated, rtt);
+        Ok(rtt)
+    })
+}
+
+// Send one query and wait for the response carrying its ID, returning its length, header and RTT
+fn exchange(socket: &UdpSocket, id: u16, request: &[u8], timeout: Duration, buffer: &mut [u8])
+            -> Result<(usize, DnsResponse, f64)> {
+    let send_time = Instant::now();
+    let deadline = send_time + timeout;
+    socket.send(request).context("Failed to send query")?;
+
+    // Skip late answers to earlier probes and anything that isn't a DNS response
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            bail!("timed out");
+        }
+        socket.set_read_timeout(Some(deadline - now))?;
+        let len = match socket.recv(buffer) {
+            Ok(len) => len,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock
+                || e.kind() == std::io::ErrorKind::TimedOut => bail!("timed out"),
+            Err(e) => return Err(e).context("Failed to receive response"),
+        };
+        let rtt = send_time.elapsed().as_secs_f64() * 1000.0;
+
+        let response = match parse_response(&buffer[..len]) {
+            Ok(response) => response,
+            Err(e) => {
+                println!("  - Ignoring malformed response: {}", e);
+                continue;
+            }
+        };
+        if response.id != id {
+            println!("  - Ignoring response with ID {} (expected {})", response.id, id);
+            continue;
+        }
+        return Ok((len, response, rtt));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    // A response header for `query` with the given ID, flags byte 2 and rcode, and one answer
+    fn reply(query: &[u8], id: u16, flags: u8, rcode: u8) -> Vec<u8> {
+        let mut reply = query.to_vec();
+        reply[0..2].copy_from_slice(&id.to_be_bytes());
+        reply[2] = 0x80 | flags;
+        reply[3] = 0x80 | rcode;
+        reply[6..8].copy_from_slice(&1u16.to_be_bytes());
+        reply
+    }
+
+    // A client socket connected to a responder on 127.0.0.1
+    fn sockets() -> (UdpSocket, UdpSocket) {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.connect(server.local_addr().unwrap()).unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn encodes_queries() {
+        let query = build_query(0x1234, "example.com.", DnsType::Aaaa).unwrap();
+        assert_eq!(query, b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\
+                            \x07example\x03com\x00\x00\x1c\x00\x01");
+        assert!(build_query(1, &"a".repeat(64), DnsType::A).is_err());
+    }
+
+    #[test]
+    fn parses_response_headers() {
+        let query = build_query(7, "example.com", DnsType::A).unwrap();
+        let response = parse_response(&reply(&query, 7, 0x02, 3)).unwrap();
+        assert_eq!((response.id, response.rcode, response.answers, response.truncated), (7, 3, 1, true));
+        assert_eq!(rcode_name(response.rcode), "NXDOMAIN");
+        assert!(parse_response(&query).is_err());
+        assert!(parse_response(&query[..11]).is_err());
+    }
+
+    #[test]
+    fn matches_the_reply_to_its_query() {
+        let (client, server) = sockets();
+        let responder = thread::spawn(move || {
+            let mut buffer = [0u8; 512];
+            let (len, peer) = server.recv_from(&mut buffer).unwrap();
+            let query = buffer[..len].to_vec();
+            let id = u16::from_be_bytes([query[0], query[1]]);
+            // A datagram cut short, a late answer to another query, then the real answer
+            server.send_to(&reply(&query, id, 0, 0)[..6], peer).unwrap();
+            server.send_to(&reply(&query, id.wrapping_sub(1), 0, 0), peer).unwrap();
+            server.send_to(&reply(&query, id, 0x02, 2), peer).unwrap();
+            query
+        });
+
+        let request = build_query(0xbeef, "example.com", DnsType::A).unwrap();
+        let mut buffer = [0u8; 4096];
+        let (len, response, rtt) = exchange(&client, 0xbeef, &request, Duration::from_secs(5), &mut buffer).unwrap();
+        assert_eq!(responder.join().unwrap(), request);
+        assert_eq!(len, request.len());
+        assert_eq!(response.id, 0xbeef);
+        assert_eq!(rcode_name(response.rcode), "SERVFAIL");
+        assert!(response.truncated);
+        assert!(rtt >= 0.0);
+    }
+
+    #[test]
+    fn times_out_without_a_matching_reply() {
+        let (client, server) = sockets();
+        let request = build_query(1, "example.com", DnsType::A).unwrap();
+        let mut buffer = [0u8; 4096];
+        let error = exchange(&client, 1, &request, Duration::from_millis(50), &mut buffer).unwrap_err();
+        assert_eq!(error.to_string(), "timed out");
+        drop(server);
+    }
+}
@@ -0,0 +1,350 @@
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, anyhow, bail};
+use clap::ValueEnum;
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::config::PingConfig;
+use crate::ping::run_probe_loop;
+#[cfg(not(target_os = "linux"))]
+use crate::config::SourceBinding;
+#[cfg(target_os = "linux")]
+use crate::socket::{apply_source, SourceBinding};
+
+// HTTP method used for each probe
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HttpMethod {
+    Head,
+    Get,
+}
+
+impl HttpMethod {
+    fn as_str(&self) -> &'static str {
+        match self {
+            HttpMethod::Head => "HEAD",
+            HttpMethod::Get => "GET",
+        }
+    }
+}
+
+// A parsed plain-HTTP destination
+#[derive(Clone, Debug)]
+pub struct HttpTarget {
+    pub url: String,
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+    pub method: HttpMethod,
+}
+
+impl HttpTarget {
+    // Parse an `http://host[:port][/path]` URL
+    pub fn parse(url: &str, method: HttpMethod) -> Result<Self> {
+        if url.starts_with("https://") {
+            bail!("HTTPS is not supported, use a plain http:// URL: {}", url);
+        }
+        let rest = url.strip_prefix("http://")
+            .ok_or_else(|| anyhow!("Not an http:// URL: {}", url))?;
+
+        // Split the authority from the path
+        let (authority, path) = match rest.find('/') {
+            Some(idx) => (&rest[..idx], &rest[idx..]),
+            None => (rest, "/"),
+        };
+        if authority.is_empty() {
+            bail!("Missing host in URL: {}", url);
+        }
+
+        // Split the port off the host, taking care of bracketed IPv6 literals
+        let (host, port) = if let Some(bracketed) = authority.strip_prefix('[') {
+            let end = bracketed.find(']')
+                .ok_or_else(|| anyhow!("Unterminated IPv6 literal in URL: {}", url))?;
+            (&bracketed[..end], bracketed[end + 1..].strip_prefix(':'))
+        } else {
+            match authority.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            }
+        };
+        let port = match port {
+            Some(port) => port.parse::<u16>()
+                .with_context(|| format!("Invalid port in URL: {}", url))?,
+            None => 80,
+        };
+
+        Ok(HttpTarget {
+            url: url.to_string(),
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+            method,
+        })
+    }
+
+    // Value for the Host header, including the port when it isn't the default
+    fn host_header(&self) -> String {
+        let host = if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        };
+        if self.port == 80 {
+            host
+        } else {
+            format!("{}:{}", host, self.port)
+        }
+    }
+}
+
+// Per-phase timings of a single HTTP probe, in milliseconds from the start of the probe
+#[derive(Debug)]
+pub struct HttpTiming {
+    pub addr: SocketAddr,
+    pub status: u16,
+    pub bytes: usize,
+    pub dns_ms: f64,
+    pub connect_ms: f64,
+    pub ttfb_ms: f64,
+    pub total_ms: f64,
+}
+
+// Time left until the deadline, failing once it has passed
+fn remaining(deadline: Instant) -> Result<Duration> {
+    let now = Instant::now();
+    if now >= deadline {
+        bail!("timed out");
+    }
+    Ok(deadline - now)
+}
+
+// Resolve the target host, preferring IPv4 like the ICMP path does
+fn resolve(target: &HttpTarget) -> Result<SocketAddr> {
+    if let Ok(ip) = target.host.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, target.port));
+    }
+    let addrs: Vec<SocketAddr> = (target.host.as_str(), target.port)
+        .to_socket_addrs()
+        .with_context(|| format!("Failed to resolve hostname: {}", target.host))?
+        .collect();
+    addrs.iter()
+        .find(|addr| addr.is_ipv4())
+        .or_else(|| addrs.first())
+        .copied()
+        .ok_or_else(|| anyhow!("No IP addresses found for host: {}", target.host))
+}
+
+// Find the end of the response headers, returning the offset of the body
+fn header_end(response: &[u8]) -> Option<usize> {
+    response.windows(4).position(|w| w == b"\r\n\r\n").map(|idx| idx + 4)
+}
+
+// Whether a complete response has been read, based on the headers seen so far
+fn response_complete(response: &[u8], method: HttpMethod) -> bool {
+    let Some(body_start) = header_end(response) else {
+        return false;
+    };
+    if method == HttpMethod::Head {
+        return true;
+    }
+
+    let headers = String::from_utf8_lossy(&response[..body_start]).to_ascii_lowercase();
+    let body = &response[body_start..];
+    for line in headers.lines() {
+        if let Some(value) = line.strip_prefix("content-length:") {
+            if let Ok(length) = value.trim().parse::<usize>() {
+                return body.len() >= length;
+            }
+        }
+        if line.starts_with("transfer-encoding:") && line.contains("chunked") {
+            return body.ends_with(b"0\r\n\r\n");
+        }
+    }
+
+    // No framing information, read until the server closes the connection
+    false
+}
+
+// Parse the status code out of an HTTP/1.x status line
+fn parse_status(response: &[u8]) -> Result<u16> {
+    let line_end = response.iter().position(|&b| b == b'\n').unwrap_or(response.len());
+    let line = String::from_utf8_lossy(&response[..line_end]);
+    let mut parts = line.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some(version), Some(code)) if version.starts_with("HTTP/") => code.parse::<u16>()
+            .with_context(|| format!("Invalid status code: {}", code)),
+        _ => Err(anyhow!("Malformed status line: {}", line.trim_end())),
+    }
+}
+
+// Issue a single request and time each phase of it, optionally from a given
+// interface or source address and with a firewall mark, which only Linux can set
+#[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+pub fn http_probe(target: &HttpTarget, timeout: Duration,
+                  source: Option<&SourceBinding>, mark: Option<u32>) -> Result<HttpTiming> {
+    let start = Instant::now();
+    let deadline = start + timeout;
+
+    // DNS resolution
+    let addr = resolve(target)?;
+    let dns_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+    // TCP connect
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))
+        .context("Failed to create TCP socket")?;
+    #[cfg(target_os = "linux")]
+    apply_source(socket.as_raw_fd(), source, mark)
+        .context("Failed to bind to the source interface or set the firewall mark")?;
+    socket.connect_timeout(&addr.into(), remaining(deadline)?)
+        .with_context(|| format!("Failed to connect to {}", addr))?;
+    let mut stream = TcpStream::from(socket);
+    let connect_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+    // Send the request
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: bingping/{}\r\nAccept: */*\r\nConnection: close\r\n\r\n",
+        target.method.as_str(), target.path, target.host_header(), env!("CARGO_PKG_VERSION"));
+    stream.set_write_timeout(Some(remaining(deadline)?))?;
+    stream.write_all(request.as_bytes()).context("Failed to send request")?;
+
+    // Read the response, noting when the first byte arrives
+    let mut response = Vec::new();
+    let mut buffer = [0u8; 4096];
+    let mut ttfb_ms = None;
+    loop {
+        stream.set_read_timeout(Some(remaining(deadline)?))?;
+        let n = match stream.read(&mut buffer) {
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock
+                || e.kind() == std::io::ErrorKind::TimedOut => bail!("timed out"),
+            Err(e) => return Err(e).context("Failed to read response"),
+        };
+        if n == 0 {
+            break;
+        }
+        if ttfb_ms.is_none() {
+            ttfb_ms = Some(start.elapsed().as_secs_f64() * 1000.0);
+        }
+        response.extend_from_slice(&buffer[..n]);
+        if response_complete(&response, target.method) {
+            break;
+        }
+    }
+    let total_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+    let ttfb_ms = ttfb_ms.ok_or_else(|| anyhow!("Connection closed without a response"))?;
+    let status = parse_status(&response)?;
+
+    Ok(HttpTiming {
+        addr,
+        status,
+        bytes: response.len(),
+        dns_ms,
+        connect_ms,
+        ttfb_ms,
+        total_ms,
+    })
+}
+
+// Ping an HTTP endpoint, reporting per-phase timings for each probe
+pub fn http_ping(config: &PingConfig, target: &HttpTarget) -> Result<()> {
+    let timeout = Duration::from_millis(config.timeout_ms);
+
+    println!("HTTP {} {} ({}:{})", target.method.as_str(), target.url, config.ip_addr, target.port);
+
+    run_probe_loop(config, |seq| {
+        let timing = http_probe(target, timeout, config.source.as_ref(), config.mark)?;
+        println!("{} bytes from {}: seq={} status={} dns={:.1} ms connect={:.1} ms ttfb={:.1} ms time={:.1} ms",
+                 timing.bytes, timing.addr, seq, timing.status,
+                 timing.dns_ms, timing.connect_ms, timing.ttfb_ms, timing.total_ms);
+        Ok(timing.total_ms)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    // Answer one request on a local port with `response` after `delay`, handing back the request
+    fn serve_once(response: &'static [u8], delay: Duration) -> (u16, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0u8; 1024];
+            while header_end(&request).is_none() {
+                let n = stream.read(&mut buffer).unwrap();
+                request.extend_from_slice(&buffer[..n]);
+            }
+            thread::sleep(delay);
+            stream.write_all(response).unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        (port, server)
+    }
+
+    fn target(port: u16, path: &str, method: HttpMethod) -> HttpTarget {
+        HttpTarget::parse(&format!("http://127.0.0.1:{}{}", port, path), method).unwrap()
+    }
+
+    #[test]
+    fn probe_reports_status_and_phases() {
+        let (port, server) = serve_once(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n", Duration::from_millis(30));
+        let timing = http_probe(&target(port, "/health", HttpMethod::Head), Duration::from_secs(5), None, None).unwrap();
+
+        let request = server.join().unwrap();
+        assert!(request.starts_with("HEAD /health HTTP/1.1\r\n"));
+        assert!(request.contains(&format!("Host: 127.0.0.1:{}\r\n", port)));
+
+        assert_eq!(timing.status, 204);
+        assert_eq!(timing.addr, SocketAddr::from(([127, 0, 0, 1], port)));
+        assert!(timing.dns_ms <= timing.connect_ms);
+        assert!(timing.connect_ms <= timing.ttfb_ms);
+        assert!(timing.ttfb_ms <= timing.total_ms);
+        // The server held the response back, so that wait falls between connecting and the first byte
+        assert!(timing.ttfb_ms - timing.connect_ms >= 30.0);
+    }
+
+    #[test]
+    fn get_reads_the_whole_body() {
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello";
+        let (port, server) = serve_once(response, Duration::ZERO);
+        let timing = http_probe(&target(port, "/", HttpMethod::Get), Duration::from_secs(5), None, None).unwrap();
+        server.join().unwrap();
+        assert_eq!(timing.status, 200);
+        assert_eq!(timing.bytes, response.len());
+    }
+
+    #[test]
+    fn malformed_response_is_an_error() {
+        let (port, server) = serve_once(b"SPDY/9 whatever\r\n\r\n", Duration::ZERO);
+        let error = http_probe(&target(port, "/", HttpMethod::Head), Duration::from_secs(5), None, None).unwrap_err();
+        server.join().unwrap();
+        assert!(error.to_string().contains("Malformed status line"), "{}", error);
+    }
+
+    #[test]
+    fn refused_connection_is_an_error() {
+        // A port that was just free is very unlikely to have been taken again
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let error = http_probe(&target(port, "/", HttpMethod::Head), Duration::from_secs(5), None, None).unwrap_err();
+        assert!(error.to_string().starts_with("Failed to connect"), "{}", error);
+        let io_error = error.root_cause().downcast_ref::<std::io::Error>().unwrap();
+        assert_eq!(io_error.kind(), std::io::ErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn parses_urls() {
+        let target = HttpTarget::parse("http://[::1]:8080/status", HttpMethod::Get).unwrap();
+        assert_eq!((target.host.as_str(), target.port, target.path.as_str()), ("::1", 8080, "/status"));
+        assert_eq!(target.host_header(), "[::1]:8080");
+        assert!(HttpTarget::parse("https://example.com/", HttpMethod::Head).is_err());
+        assert!(HttpTarget::parse("http://example.com:99999/", HttpMethod::Head).is_err());
+    }
+}
@@ -0,0 +1,88 @@
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use image::imageops::FilterType;
+use image::{GenericImageView, Rgba};
+
+use crate::art::MAX_ART_LEN;
+use crate::display::rgb_to_ansi256;
+
+// Characters from darkest to brightest, the ones the built-in art is drawn with
+const RAMP: &[u8] = b" .:-=+*#%@";
+
+// Colours in art converted from an image
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ArtColor {
+    /// Plain characters from the brightness ramp
+    None,
+    /// Half blocks in the 256-colour palette
+    #[value(name = "256")]
+    Ansi256,
+    /// Half blocks in 24-bit colour
+    Truecolor,
+}
+
+// A pixel as red, green and blue, darkened by its transparency
+fn rgb(pixel: &Rgba<u8>) -> [u8; 3] {
+    let [r, g, b, a] = pixel.0;
+    let scale = |channel: u8| (channel as u16 * a as u16 / 255) as u8;
+    [scale(r), scale(g), scale(b)]
+}
+
+// Perceived brightness from 0 to 1
+fn luminance([r, g, b]: [u8; 3]) -> f64 {
+    (0.2126 * r as f64 + 0.7152 * g as f64 + 0.0722 * b as f64) / 255.0
+}
+
+// Escape setting the foreground and background of a half block
+fn cell_colors(color: ArtColor, top: [u8; 3], bottom: [u8; 3]) -> String {
+    match color {
+        ArtColor::Truecolor => format!("\x1b[38;2;{};{};{};48;2;{};{};{}m",
+                                       top[0], top[1], top[2], bottom[0], bottom[1], bottom[2]),
+        _ => format!("\x1b[38;5;{};48;5;{}m", rgb_to_ansi256(top), rgb_to_ansi256(bottom)),
+    }
+}
+
+// Convert an image to art `width` characters wide, usable as the banner and the echo payload
+pub fn image_to_art(path: &Path, width: u32, color: ArtColor) -> Result<String> {
+    let image = image::open(path).with_context(|| format!("Failed to read image {}", path.display()))?;
+    let (image_width, image_height) = image.dimensions();
+    // Terminal cells are about twice as tall as they are wide
+    let rows = (image_height as f64 * width as f64 / image_width as f64 / 2.0).round().max(1.0) as u32;
+
+    let mut art = String::new();
+    if color == ArtColor::None {
+        let pixels = image.resize_exact(width, rows, FilterType::Triangle).to_rgba8();
+        for row in pixels.rows() {
+            for pixel in row {
+                let level = (luminance(rgb(pixel)) * (RAMP.len() - 1) as f64).round() as usize;
+                art.push(RAMP[level] as char);
+            }
+            art.push('\n');
+        }
+    } else {
+        // Each cell shows two pixels: the upper one as the foreground of '▀', the lower as its background
+        let pixels = image.resize_exact(width, rows * 2, FilterType::Triangle).to_rgba8();
+        for y in (0..rows * 2).step_by(2) {
+            let mut current = String::new();
+            for x in 0..width {
+                let colors = cell_colors(color, rgb(pixels.get_pixel(x, y)), rgb(pixels.get_pixel(x, y + 1)));
+                // Neighbouring cells often share colours, so only changes are written
+                if colors != current {
+                    art.push_str(&colors);
+                    current = colors;
+                }
+                art.push('▀');
+            }
+            art.push_str("\x1b[0m\n");
+        }
+    }
+
+    if art.len() > MAX_ART_LEN {
+        let suggestion = if color == ArtColor::Truecolor { " or --art-color 256" } else { "" };
+        bail!("{} makes {} bytes of art, but art can be at most {} bytes; try a smaller --art-width{}",
+              path.display(), art.len(), MAX_ART_LEN, suggestion);
+    }
+    Ok(art)
+}
@@ -1,3 +1,7 @@
+// Packet building and parsing back the raw-socket modes, which only run on Linux; elsewhere much
+// of it goes unused
+#![cfg_attr(not(target_os = "linux"), allow(dead_code))]
+
 use anyhow::Result;
 
 mod args;
@@ -6,10 +10,33 @@ mod stats;
 mod display;
 mod packet;
 mod ping;
+mod http;
+mod dns;
+#[cfg(target_os = "linux")]
+mod socket;
+#[cfg(target_os = "linux")]
+mod pmtu;
+#[cfg(target_os = "linux")]
+mod netns;
+#[cfg(target_os = "linux")]
+mod bench;
+#[cfg(target_os = "linux")]
+mod transport;
+mod simulate;
+mod pcap;
+mod analyze;
+#[cfg(target_os = "linux")]
+mod serve;
+mod art;
+mod image_art;
 
-use crate::config::parse_args;
-use crate::display::{load_ascii_art, pink_text, rainbow_text};
-use crate::ping::{ping_with_raw_sockets, execute_system_ping};
+use crate::config::{parse_args, PingConfig, PingMode};
+use crate::display::style_art;
+#[cfg(target_os = "linux")]
+use crate::ping::{open_capture, ping_with_raw_sockets, ping_simulated};
+use crate::ping::execute_system_ping;
+use crate::http::http_ping;
+use crate::dns::dns_ping;
 
 fn main() -> Result<()> {
     // Parse command-line arguments into PingConfig
@@ -17,29 +44,69 @@ fn main() -> Result<()> {
     
     // Print ASCII art
     if !config.quiet {
-        let ascii_art = load_ascii_art();
-        if config.rainbow {
-            // Use rainbow colors
-            println!("{}", rainbow_text(&ascii_art));
-        } else {
-            // Use pink color
-            println!("{}", pink_text(&ascii_art));
-        }
+        println!("{}", style_art(&config.art, config.rainbow));
+    }
+    
+    // HTTP and DNS probes don't need raw sockets, path MTU discovery and benchmarks run their own loops,
+    // analysis only reads a file and serving answers other hosts' probes
+    match &config.mode {
+        PingMode::Http(target) => return http_ping(&config, target),
+        PingMode::Dns(query) => return dns_ping(&config, query),
+        #[cfg(target_os = "linux")]
+        PingMode::Pmtu => return pmtu::discover_pmtu(&config),
+        #[cfg(not(target_os = "linux"))]
+        PingMode::Pmtu => return Err(anyhow::anyhow!("Path MTU discovery is only supported on Linux")),
+        PingMode::Analyze(path) => return analyze::analyze(path),
+        #[cfg(target_os = "linux")]
+        PingMode::Serve(options) => return serve::serve(&config, options),
+        #[cfg(not(target_os = "linux"))]
+        PingMode::Serve(options) => match *options {},
+        #[cfg(target_os = "linux")]
+        PingMode::Benchmark { batch } => return bench::benchmark(&config, *batch),
+        #[cfg(not(target_os = "linux"))]
+        PingMode::Benchmark { .. } => return Err(anyhow::anyhow!("Benchmark mode is only supported on Linux")),
+        PingMode::Icmp | PingMode::IcmpTimestamp => {}
+    }
+    
+    ping_icmp(&config)
+}
+
+#[cfg(target_os = "linux")]
+fn ping_icmp(config: &PingConfig) -> Result<()> {
+    // Create the capture file up front, so a bad path is reported as such instead of being taken
+    // for a raw socket failure and falling back to the system ping
+    let capture = open_capture(config)?;
+    
+    // A simulated network stands in for raw sockets, so there's nothing to fall back from
+    if let Some(spec) = &config.simulate {
+        return ping_simulated(config, spec, capture);
     }
     
     // Try to use raw sockets for custom ping implementation
-    match ping_with_raw_sockets(&config) {
+    match ping_with_raw_sockets(config, capture) {
         Ok(_) => Ok(()),
         Err(e) => {
-            // If the error is due to permissions (usually for raw sockets), fall back to system ping
-            if let Some(io_err) = e.root_cause().downcast_ref::<std::io::Error>() {
-                eprintln!("Failed to use raw sockets: {}", io_err);
-                eprintln!("Falling back to system ping command");
-                execute_system_ping(&config)
-            } else {
+            // If the error is due to permissions (usually for raw sockets), fall back to system ping.
+            // The system ping can only send echo requests, so other probe types just fail.
+            let fallback = matches!(config.mode, PingMode::Icmp);
+            match e.root_cause().downcast_ref::<std::io::Error>() {
+                Some(io_err) if fallback => {
+                    eprintln!("Failed to use raw sockets: {}", io_err);
+                    eprintln!("Falling back to system ping command");
+                    execute_system_ping(config)
+                },
                 // For other errors, just return the error
-                Err(e)
+                _ => Err(e),
             }
         }
     }
 }
+
+// The raw-socket implementation is Linux-only, elsewhere echo requests go through the system ping
+#[cfg(not(target_os = "linux"))]
+fn ping_icmp(config: &PingConfig) -> Result<()> {
+    match config.mode {
+        PingMode::Icmp => execute_system_ping(config),
+        _ => Err(anyhow::anyhow!("ICMP timestamp requests are only supported on Linux")),
+    }
+}
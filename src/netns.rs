@@ -0,0 +1,24 @@
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+// Where `ip netns add` bind-mounts named network namespaces
+const NETNS_RUN_DIR: &str = "/var/run/netns";
+
+// Move the calling thread into a named network namespace. Threads spawned afterwards
+// inherit it, so this has to happen before any sockets or worker threads are created.
+pub fn enter_netns(name: &str) -> Result<()> {
+    let path = Path::new(NETNS_RUN_DIR).join(name);
+    let file = File::open(&path)
+        .with_context(|| format!("Failed to open network namespace {}", path.display()))?;
+
+    let result = unsafe { libc::setns(file.as_raw_fd(), libc::CLONE_NEWNET) };
+    if result < 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("Failed to enter network namespace {}", name));
+    }
+    Ok(())
+}
@@ -1,55 +1,572 @@
-use pnet::packet::icmp::{IcmpTypes, MutableIcmpPacket};
+use std::net::Ipv4Addr;
+
+use chrono::{Timelike, Utc};
+use clap::ValueEnum;
+use pnet::packet::icmp::{IcmpCode, IcmpPacket, IcmpType, IcmpTypes, MutableIcmpPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::{self, Ipv4Packet, MutableIpv4Packet};
 use pnet::packet::MutablePacket;
 
-use crate::display::load_ascii_art;
+use crate::display::sgr_prefix;
+
+// Length of the ICMP header: type, code, checksum and the 4-byte rest of header
+pub const ICMP_HEADER_LEN: usize = 8;
+
+// Length of an ICMP Timestamp message: header plus originate, receive and transmit times
+pub const ICMP_TIMESTAMP_LEN: usize = ICMP_HEADER_LEN + 12;
+
+// Build a generic ICMP message into `buffer`: type, code, the 4-byte rest of the header
+// and the payload that follows it. The checksum is filled in and the message length returned.
+pub fn build_icmp_message(buffer: &mut [u8], icmp_type: IcmpType, icmp_code: IcmpCode,
+                          rest_of_header: [u8; 4], payload: &[u8]) -> usize {
+    let length = ICMP_HEADER_LEN + payload.len();
+    let buffer = &mut buffer[..length];
 
-// Create an ICMP packet with ASCII art data
-pub fn create_icmp_packet(buffer: &mut [u8], sequence: u16, identifier: u16, size: usize) -> usize {
     // Clear the buffer first
-    buffer.iter_mut().for_each(|b| *b = 0);
-    
-    // Create the ICMP packet
+    buffer.fill(0);
+
     let mut icmp_packet = MutableIcmpPacket::new(buffer).unwrap();
-    
-    // Set ICMP type to echo request
-    icmp_packet.set_icmp_type(IcmpTypes::EchoRequest);
-    icmp_packet.set_icmp_code(pnet::packet::icmp::IcmpCode(0));
-    
-    // Set echo request data
+    icmp_packet.set_icmp_type(icmp_type);
+    icmp_packet.set_icmp_code(icmp_code);
+
     {
-        let echo_request_buffer = icmp_packet.payload_mut();
-        
-        // Set identifier (byte 0-1)
-        echo_request_buffer[0] = (identifier >> 8) as u8;
-        echo_request_buffer[1] = (identifier & 0xFF) as u8;
-        
-        // Set sequence number (byte 2-3)
-        echo_request_buffer[2] = (sequence >> 8) as u8;
-        echo_request_buffer[3] = (sequence & 0xFF) as u8;
-        
-        // Get ASCII art and use it as payload
-        let ascii_art = load_ascii_art();
-        let ascii_bytes = ascii_art.as_bytes();
-        
-        // Calculate how much of the ASCII art we can fit
-        let max_payload_size = echo_request_buffer.len() - 4; // Reserve 4 bytes for ID and seq
-        let art_size = ascii_bytes.len().min(max_payload_size);
-        
-        // Copy as much of the ASCII art as will fit in the payload
-        echo_request_buffer[4..4+art_size].copy_from_slice(&ascii_bytes[0..art_size]);
-        
-        // Fill any remaining space with a pattern
-        for i in (4 + art_size)..echo_request_buffer.len() {
-            echo_request_buffer[i] = b'#';
-        }
-    }
-    
+        let body = icmp_packet.payload_mut();
+        body[0..4].copy_from_slice(&rest_of_header);
+        body[4..].copy_from_slice(payload);
+    }
+
     // Calculate and set the checksum
     let checksum = pnet::packet::icmp::checksum(&icmp_packet.to_immutable());
     icmp_packet.set_checksum(checksum);
-    
-    println!("Created ICMP Echo Request with ASCII art: ID={}, Seq={}, Checksum={:x}", 
+
+    length
+}
+
+// Length of an IPv4 header without options
+pub const IPV4_HEADER_LEN: usize = 20;
+
+// Put an IPv4 header, with any options, in front of an ICMP message
+pub fn build_ipv4_datagram(source: Ipv4Addr, destination: Ipv4Addr, ttl: u8, tos: u8,
+                           options: &[u8], message: &[u8]) -> Vec<u8> {
+    let header_len = IPV4_HEADER_LEN + options.len();
+    let mut datagram = vec![0u8; header_len + message.len()];
+    {
+        let mut ip = MutableIpv4Packet::new(&mut datagram).unwrap();
+        ip.set_version(4);
+        ip.set_header_length((header_len / 4) as u8);
+        ip.set_dscp(tos >> 2);
+        ip.set_ecn(tos & 0x03);
+        ip.set_total_length((header_len + message.len()) as u16);
+        ip.set_ttl(ttl);
+        ip.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
+        ip.set_source(source);
+        ip.set_destination(destination);
+    }
+    datagram[IPV4_HEADER_LEN..header_len].copy_from_slice(options);
+    let checksum = ipv4::checksum(&Ipv4Packet::new(&datagram).unwrap());
+    MutableIpv4Packet::new(&mut datagram).unwrap().set_checksum(checksum);
+    datagram[header_len..].copy_from_slice(message);
+    datagram
+}
+
+// Rest of header used by echo and timestamp messages: identifier then sequence number
+fn identifier_and_sequence(identifier: u16, sequence: u16) -> [u8; 4] {
+    let id = identifier.to_be_bytes();
+    let seq = sequence.to_be_bytes();
+    [id[0], id[1], seq[0], seq[1]]
+}
+
+// Length of the monotonic send time carried at the start of echo data
+pub const ECHO_TIMESTAMP_LEN: usize = 8;
+
+// Create an ICMP packet with room for its send time followed by ASCII art data. The room is
+// left out when the payload is too small to hold it.
+pub fn create_icmp_packet(buffer: &mut [u8], sequence: u16, identifier: u16, size: usize, art: &[u8]) -> usize {
+    let max_payload_size = buffer.len() - ICMP_HEADER_LEN;
+
+    // The send time goes first, like iputils does, and is filled in by stamp_echo_packet
+    let art_start = echo_art_start(max_payload_size);
+    let mut payload = vec![0u8; art_start];
+    payload.extend(echo_art_data(art, max_payload_size - art_start));
+
+    build_icmp_message(buffer, IcmpTypes::EchoRequest, IcmpCode(0),
+                       identifier_and_sequence(identifier, sequence), &payload);
+
+    let checksum = u16::from_be_bytes([buffer[2], buffer[3]]);
+    println!("Created ICMP Echo Request with ASCII art: ID={}, Seq={}, Checksum={:x}",
              identifier, sequence, checksum);
-    
+
     size
-} 
\ No newline at end of file
+}
+
+// Where the art starts in `size` bytes of echo data: after the send time, if it fits
+pub fn echo_art_start(size: usize) -> usize {
+    if size >= ECHO_TIMESTAMP_LEN { ECHO_TIMESTAMP_LEN } else { 0 }
+}
+
+// Length of the longest prefix of `art` that fits in `len` bytes without cutting a UTF-8 character
+// or a colour escape in two
+pub fn art_prefix_len(art: &[u8], len: usize) -> usize {
+    if len >= art.len() {
+        return art.len();
+    }
+    // Back up over continuation bytes to the start of the character that doesn't fit
+    let mut end = len;
+    while end > 0 && art[end] & 0xc0 == 0x80 {
+        end -= 1;
+    }
+    // Then to the start of the last escape, unless it ends before the cut
+    let Some(start) = art[..end].iter().rposition(|&b| b == 0x1b) else {
+        return end;
+    };
+    let escape = art[start..].utf8_chunks().next().and_then(|chunk| sgr_prefix(chunk.valid()));
+    match escape {
+        Some(sgr) if start + sgr.len() <= end => end,
+        _ => start,
+    }
+}
+
+// As much of the art as fits in `len` bytes, ending on a whole character and escape, with any
+// remaining space filled with a pattern
+pub fn echo_art_data(art: &[u8], len: usize) -> Vec<u8> {
+    let mut data = vec![b'#'; len];
+    let art_size = art_prefix_len(art, len);
+    data[..art_size].copy_from_slice(&art[..art_size]);
+    data
+}
+
+// Offsets where the data that came back differs from what was sent, counting missing and extra bytes
+pub fn data_mismatches(expected: &[u8], received: &[u8]) -> Vec<usize> {
+    (0..expected.len().max(received.len()))
+        .filter(|&offset| expected.get(offset) != received.get(offset))
+        .collect()
+}
+
+// Whether an ICMP message's checksum is correct
+pub fn icmp_checksum_ok(packet: &IcmpPacket) -> bool {
+    pnet::packet::icmp::checksum(packet) == packet.get_checksum()
+}
+
+// Write the send time (monotonic nanoseconds) into an echo request built by create_icmp_packet
+// just before it goes out, and update the checksum. Returns false if there's no room for it.
+pub fn stamp_echo_packet(packet: &mut [u8], sent_ns: u64) -> bool {
+    let start = ICMP_HEADER_LEN;
+    let Some(stamp) = packet.get_mut(start..start + ECHO_TIMESTAMP_LEN) else {
+        return false;
+    };
+    stamp.copy_from_slice(&sent_ns.to_be_bytes());
+
+    let mut icmp_packet = MutableIcmpPacket::new(packet).unwrap();
+    icmp_packet.set_checksum(0);
+    let checksum = pnet::packet::icmp::checksum(&icmp_packet.to_immutable());
+    icmp_packet.set_checksum(checksum);
+    true
+}
+
+// Reuse an echo request for another probe, with a new sequence number and send time
+pub fn restamp_echo_packet(packet: &mut [u8], sequence: u16, sent_ns: u64) -> bool {
+    if packet.len() < ICMP_HEADER_LEN {
+        return false;
+    }
+    packet[6..8].copy_from_slice(&sequence.to_be_bytes());
+    stamp_echo_packet(packet, sent_ns)
+}
+
+// Send time carried in an echo payload (after the identifier and sequence), and the data after it
+pub fn split_echo_payload(payload: &[u8]) -> (Option<u64>, &[u8]) {
+    let data = payload.get(4..).unwrap_or_default();
+    match data.split_first_chunk::<ECHO_TIMESTAMP_LEN>() {
+        Some((sent_ns, rest)) => (Some(u64::from_be_bytes(*sent_ns)), rest),
+        None => (None, data),
+    }
+}
+
+// Milliseconds since midnight UTC, the clock used by ICMP Timestamp messages
+pub fn ms_since_midnight_utc() -> u32 {
+    let now = Utc::now();
+    now.num_seconds_from_midnight() * 1000 + now.nanosecond() / 1_000_000
+}
+
+// Create an ICMP Timestamp Request carrying the current originate time
+pub fn create_timestamp_packet(buffer: &mut [u8], sequence: u16, identifier: u16) -> usize {
+    let originate = ms_since_midnight_utc();
+
+    // Originate timestamp, receive and transmit are left for the replier to fill in
+    let mut payload = [0u8; 12];
+    payload[0..4].copy_from_slice(&originate.to_be_bytes());
+
+    let length = build_icmp_message(buffer, IcmpTypes::Timestamp, IcmpCode(0),
+                                    identifier_and_sequence(identifier, sequence), &payload);
+
+    println!("Created ICMP Timestamp Request: ID={}, Seq={}, Originate={}",
+             identifier, sequence, originate);
+
+    length
+}
+
+// Originate, receive and transmit times from an ICMP Timestamp Reply
+pub struct IcmpTimestamps {
+    pub originate: u32,
+    pub receive: u32,
+    pub transmit: u32,
+}
+
+// Parse the timestamps following the identifier and sequence in a Timestamp Reply payload
+pub fn parse_timestamp_reply(payload: &[u8]) -> Option<IcmpTimestamps> {
+    if payload.len() < 16 {
+        return None;
+    }
+    let field = |offset: usize| u32::from_be_bytes(payload[offset..offset + 4].try_into().unwrap());
+    Some(IcmpTimestamps {
+        originate: field(4),
+        receive: field(8),
+        transmit: field(12),
+    })
+}
+
+// Signed difference between two ms-since-midnight values, accounting for the day wrapping
+pub fn timestamp_diff(later: u32, earlier: u32) -> i64 {
+    const DAY_MS: i64 = 86_400_000;
+    let mut diff = (later as i64 - earlier as i64) % DAY_MS;
+    if diff > DAY_MS / 2 {
+        diff -= DAY_MS;
+    } else if diff < -DAY_MS / 2 {
+        diff += DAY_MS;
+    }
+    diff
+}
+
+// IPv4 option types we send and parse
+const IPOPT_EOL: u8 = 0;
+const IPOPT_NOP: u8 = 1;
+const IPOPT_RR: u8 = 7;
+const IPOPT_TS: u8 = 68;
+
+// Largest space available for IPv4 options
+const MAX_IP_OPTIONS_LEN: usize = 40;
+
+// What the IP Timestamp option asks each hop to record
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IpTimestampMode {
+    /// Timestamps only
+    Tsonly,
+    /// Each hop's address followed by its timestamp
+    Tsandaddr,
+}
+
+// An IPv4 option carried by every probe
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IpOption {
+    RecordRoute,
+    Timestamp(IpTimestampMode),
+}
+
+impl IpOption {
+    // Encode the option, padded to the full 40 bytes of option space
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut options = vec![IPOPT_EOL; MAX_IP_OPTIONS_LEN];
+        match self {
+            IpOption::RecordRoute => {
+                // A leading NOP keeps the addresses 4-byte aligned, leaving room for 9 hops
+                options[0] = IPOPT_NOP;
+                options[1] = IPOPT_RR;
+                options[2] = (MAX_IP_OPTIONS_LEN - 1) as u8;
+                options[3] = 4;
+            },
+            IpOption::Timestamp(mode) => {
+                // 9 timestamps, or 4 address/timestamp pairs
+                let (length, flag) = match mode {
+                    IpTimestampMode::Tsonly => (40, 0),
+                    IpTimestampMode::Tsandaddr => (36, 1),
+                };
+                options[0] = IPOPT_TS;
+                options[1] = length;
+                options[2] = 5;
+                options[3] = flag;
+            },
+        }
+        options
+    }
+}
+
+// Contents of the options echoed back in a reply's IPv4 header
+#[derive(Debug, Default)]
+pub struct RecordedOptions {
+    pub route: Vec<Ipv4Addr>,
+    pub timestamps: Vec<(Option<Ipv4Addr>, u32)>,
+    pub timestamp_overflow: u8,
+}
+
+impl RecordedOptions {
+    pub fn is_empty(&self) -> bool {
+        self.route.is_empty() && self.timestamps.is_empty() && self.timestamp_overflow == 0
+    }
+}
+
+// Parse the Record Route and Timestamp options out of an IPv4 header's option bytes
+pub fn parse_ip_options(options: &[u8]) -> RecordedOptions {
+    let mut recorded = RecordedOptions::default();
+    let mut i = 0;
+    while i < options.len() {
+        let option_type = options[i];
+        if option_type == IPOPT_EOL {
+            break;
+        }
+        if option_type == IPOPT_NOP {
+            i += 1;
+            continue;
+        }
+        let Some(&length) = options.get(i + 1) else {
+            break;
+        };
+        let length = length as usize;
+        if length < 2 || i + length > options.len() {
+            break;
+        }
+        let option = &options[i..i + length];
+
+        // The pointer is 1-based and points just past the last recorded entry
+        let filled = |start: usize| {
+            let end = (option.get(2).copied().unwrap_or(0) as usize).saturating_sub(1).min(option.len());
+            &option[start.min(end)..end]
+        };
+        let addr = |bytes: &[u8]| Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]);
+        let time = |bytes: &[u8]| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+
+        match option_type {
+            IPOPT_RR if length >= 3 => {
+                recorded.route = filled(3).chunks_exact(4).map(addr).collect();
+            },
+            IPOPT_TS if length >= 4 => {
+                recorded.timestamp_overflow = option[3] >> 4;
+                recorded.timestamps = match option[3] & 0x0F {
+                    0 => filled(4).chunks_exact(4).map(|entry| (None, time(entry))).collect(),
+                    _ => filled(4).chunks_exact(8)
+                        .map(|entry| (Some(addr(&entry[0..4])), time(&entry[4..8])))
+                        .collect(),
+                };
+            },
+            _ => {}
+        }
+        i += length;
+    }
+    recorded
+}
+
+// Note `hop` passing at `time_ms` in the Record Route and Timestamp options, as each router on the
+// path does, counting an overflow once a timestamp option is full
+pub fn record_hop(options: &mut [u8], hop: Ipv4Addr, time_ms: u32) {
+    let mut i = 0;
+    while i < options.len() {
+        let option_type = options[i];
+        if option_type == IPOPT_EOL {
+            break;
+        }
+        if option_type == IPOPT_NOP {
+            i += 1;
+            continue;
+        }
+        let Some(&length) = options.get(i + 1) else {
+            break;
+        };
+        let length = length as usize;
+        if length < 2 || i + length > options.len() {
+            break;
+        }
+        let option = &mut options[i..i + length];
+        let pointer = option.get(2).copied().unwrap_or(0) as usize;
+
+        let entry = match option_type {
+            IPOPT_RR if length >= 3 && pointer >= 4 => hop.octets().to_vec(),
+            IPOPT_TS if length >= 4 && pointer >= 5 => match option[3] & 0x0F {
+                0 => time_ms.to_be_bytes().to_vec(),
+                1 => [hop.octets(), time_ms.to_be_bytes()].concat(),
+                // Prespecified addresses aren't something we send
+                _ => Vec::new(),
+            },
+            _ => Vec::new(),
+        };
+        if !entry.is_empty() {
+            if pointer - 1 + entry.len() <= length {
+                option[pointer - 1..pointer - 1 + entry.len()].copy_from_slice(&entry);
+                option[2] += entry.len() as u8;
+            } else if option_type == IPOPT_TS {
+                let overflow = (option[3] >> 4).saturating_add(1).min(15);
+                option[3] = overflow << 4 | option[3] & 0x0F;
+            }
+        }
+        i += length;
+    }
+}
+
+// DSCP code points by name: expedited forwarding, assured forwarding and class selectors
+const DSCP_NAMES: [(&str, u8); 24] = [
+    ("DF", 0), ("LE", 1), ("VA", 44), ("EF", 46),
+    ("AF11", 10), ("AF12", 12), ("AF13", 14),
+    ("AF21", 18), ("AF22", 20), ("AF23", 22),
+    ("AF31", 26), ("AF32", 28), ("AF33", 30),
+    ("AF41", 34), ("AF42", 36), ("AF43", 38),
+    ("CS0", 0), ("CS1", 8), ("CS2", 16), ("CS3", 24),
+    ("CS4", 32), ("CS5", 40), ("CS6", 48), ("CS7", 56),
+];
+
+// Parse a DSCP given by name (EF, AF41, CS1...) or as a number from 0 to 63
+pub fn parse_dscp(value: &str) -> Result<u8, String> {
+    let upper = value.trim().to_ascii_uppercase();
+    if let Some(&(_, dscp)) = DSCP_NAMES.iter().find(|(name, _)| *name == upper) {
+        return Ok(dscp);
+    }
+    match upper.parse::<u8>() {
+        Ok(dscp) if dscp < 64 => Ok(dscp),
+        _ => Err(format!("Unknown DSCP '{}', expected a name like EF, AF41, CS1 or a number 0-63", value)),
+    }
+}
+
+// Name of a DSCP code point, or its number if it has no standard name
+pub fn dscp_name(dscp: u8) -> String {
+    DSCP_NAMES.iter()
+        .find(|(_, code)| *code == dscp)
+        .map(|(name, _)| name.to_string())
+        .unwrap_or_else(|| dscp.to_string())
+}
+
+// Parse a full TOS byte given in decimal or as 0x-prefixed hex
+pub fn parse_tos(value: &str) -> Result<u8, String> {
+    let value = value.trim();
+    let parsed = match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => value.parse::<u8>(),
+    };
+    parsed.map_err(|_| format!("Invalid TOS '{}', expected 0-255 or 0x00-0xff", value))
+}
+
+// ECN codepoint carried in the low two bits of the TOS byte
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Ecn {
+    /// Not ECN-capable transport
+    NotEct,
+    /// ECN-capable transport (1)
+    Ect1,
+    /// ECN-capable transport (0)
+    Ect0,
+    /// Congestion experienced
+    Ce,
+}
+
+impl Ecn {
+    pub fn bits(self) -> u8 {
+        match self {
+            Ecn::NotEct => 0,
+            Ecn::Ect1 => 1,
+            Ecn::Ect0 => 2,
+            Ecn::Ce => 3,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn art_is_cut_between_characters() {
+        let art = "a▀b".as_bytes();
+        assert_eq!(art_prefix_len(art, 2), 1);
+        assert_eq!(art_prefix_len(art, 4), 4);
+        assert_eq!(art_prefix_len(art, 10), art.len());
+    }
+
+    #[test]
+    fn art_is_cut_between_escapes() {
+        let art = "\x1b[38;5;213;48;5;17m▀▀\x1b[0m\n".as_bytes();
+        let escape_len = "\x1b[38;5;213;48;5;17m".len();
+        for len in 1..escape_len {
+            assert_eq!(art_prefix_len(art, len), 0, "cut at {}", len);
+        }
+        assert_eq!(art_prefix_len(art, escape_len), escape_len);
+        assert_eq!(art_prefix_len(art, escape_len + 4), escape_len + 3);
+        let reset = escape_len + 6;
+        assert_eq!(art_prefix_len(art, reset + 2), reset);
+        assert_eq!(&echo_art_data(art, reset + 2)[reset..], b"##");
+    }
+
+    // Fill in the first entries of an option from `to_bytes`, which start at byte 4 either way,
+    // and move its pointer at `pointer` past them
+    fn record(mut options: Vec<u8>, pointer: usize, entries: &[u8]) -> Vec<u8> {
+        options[4..4 + entries.len()].copy_from_slice(entries);
+        options[pointer] += entries.len() as u8;
+        options
+    }
+
+    #[test]
+    fn parses_record_route() {
+        let options = record(IpOption::RecordRoute.to_bytes(), 3, &[10, 0, 0, 1, 10, 0, 1, 1]);
+        let recorded = parse_ip_options(&options);
+        assert_eq!(recorded.route, [Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 1, 1)]);
+        assert!(recorded.timestamps.is_empty());
+        assert!(parse_ip_options(&IpOption::RecordRoute.to_bytes()).is_empty());
+    }
+
+    #[test]
+    fn parses_timestamps_in_each_mode() {
+        let mut options = record(IpOption::Timestamp(IpTimestampMode::Tsonly).to_bytes(), 2, &[0, 0, 0, 7, 0, 0, 1, 0]);
+        options[3] |= 2 << 4;
+        let recorded = parse_ip_options(&options);
+        assert_eq!(recorded.timestamps, [(None, 7), (None, 256)]);
+        assert_eq!(recorded.timestamp_overflow, 2);
+
+        let options = record(IpOption::Timestamp(IpTimestampMode::Tsandaddr).to_bytes(), 2, &[10, 0, 0, 1, 0, 0, 0, 9]);
+        assert_eq!(parse_ip_options(&options).timestamps, [(Some(Ipv4Addr::new(10, 0, 0, 1)), 9)]);
+
+        // Prespecified addresses have the same layout, with the unfilled ones past the pointer
+        let mut options = record(IpOption::Timestamp(IpTimestampMode::Tsandaddr).to_bytes(), 2, &[10, 0, 0, 1, 0, 0, 0, 9]);
+        options[3] = 3;
+        options[12..16].copy_from_slice(&[10, 0, 0, 2]);
+        assert_eq!(parse_ip_options(&options).timestamps, [(Some(Ipv4Addr::new(10, 0, 0, 1)), 9)]);
+    }
+
+    #[test]
+    fn stops_at_truncated_and_overlong_options() {
+        // Cut short, so the length runs past the end of the header
+        let options = record(IpOption::RecordRoute.to_bytes(), 3, &[10, 0, 0, 1]);
+        assert!(parse_ip_options(&options[..12]).is_empty());
+        assert!(parse_ip_options(&[IPOPT_NOP, IPOPT_RR]).is_empty());
+        // Lengths too long for the option space, or too short to hold the length byte itself
+        let mut options = options;
+        options[2] = 200;
+        assert!(parse_ip_options(&options).is_empty());
+        assert!(parse_ip_options(&[IPOPT_RR, 1, 4, 10]).is_empty());
+        // Nothing after the end of the option list counts
+        assert!(parse_ip_options(&[IPOPT_EOL, IPOPT_RR, 7, 8, 10, 0, 0, 1]).is_empty());
+    }
+
+    #[test]
+    fn clamps_an_overlong_pointer() {
+        // A pointer past the end of the option only reads as far as the option goes
+        let options = [IPOPT_RR, 11, 40, 10, 0, 0, 1, 10, 0, 0, 2];
+        assert_eq!(parse_ip_options(&options).route, [Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2)]);
+        let options = [IPOPT_TS, 10, 40, 0, 0, 0, 0, 5, 0, 0];
+        assert_eq!(parse_ip_options(&options).timestamps, [(None, 5)]);
+    }
+
+    #[test]
+    fn records_hops_until_the_options_fill() {
+        let hops: Vec<Ipv4Addr> = (1..=10).map(|n| Ipv4Addr::new(10, 0, 0, n)).collect();
+        let mut route = IpOption::RecordRoute.to_bytes();
+        let mut stamps = IpOption::Timestamp(IpTimestampMode::Tsandaddr).to_bytes();
+        let mut times = IpOption::Timestamp(IpTimestampMode::Tsonly).to_bytes();
+        for (time, &hop) in hops.iter().enumerate() {
+            record_hop(&mut route, hop, time as u32);
+            record_hop(&mut stamps, hop, time as u32);
+            record_hop(&mut times, hop, time as u32);
+        }
+
+        assert_eq!(parse_ip_options(&route).route, hops[..9]);
+        let recorded = parse_ip_options(&stamps);
+        assert_eq!(recorded.timestamps, (0..4).map(|n| (Some(hops[n]), n as u32)).collect::<Vec<_>>());
+        assert_eq!(recorded.timestamp_overflow, 6);
+        let recorded = parse_ip_options(&times);
+        assert_eq!(recorded.timestamps, (0..9).map(|n| (None, n)).collect::<Vec<_>>());
+        assert_eq!(recorded.timestamp_overflow, 1);
+    }
+}
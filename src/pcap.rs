@@ -0,0 +1,368 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result, anyhow, bail};
+
+// Magic number of a classic pcap file with nanosecond timestamps
+const PCAP_MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+const PCAP_VERSION_MAJOR: u16 = 2;
+const PCAP_VERSION_MINOR: u16 = 4;
+
+// Packets are raw IP datagrams with no link-layer header in front
+const LINKTYPE_RAW: u32 = 101;
+
+// Longest packet kept in full
+const SNAPLEN: u32 = 65535;
+
+// Writes packets to a pcap file that Wireshark and tcpdump can open
+pub struct PcapWriter {
+    out: BufWriter<File>,
+    packets: u64,
+}
+
+impl PcapWriter {
+    // Create the file and write the pcap global header
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(&PCAP_MAGIC_NANOS.to_le_bytes())?;
+        out.write_all(&PCAP_VERSION_MAJOR.to_le_bytes())?;
+        out.write_all(&PCAP_VERSION_MINOR.to_le_bytes())?;
+        // Timezone offset and timestamp accuracy, always zero
+        out.write_all(&0i32.to_le_bytes())?;
+        out.write_all(&0u32.to_le_bytes())?;
+        out.write_all(&SNAPLEN.to_le_bytes())?;
+        out.write_all(&LINKTYPE_RAW.to_le_bytes())?;
+        Ok(PcapWriter { out, packets: 0 })
+    }
+
+    // Append one IP datagram captured at `time`
+    pub fn write(&mut self, time: SystemTime, datagram: &[u8]) -> io::Result<()> {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let captured = datagram.len().min(SNAPLEN as usize);
+        self.out.write_all(&(since_epoch.as_secs() as u32).to_le_bytes())?;
+        self.out.write_all(&since_epoch.subsec_nanos().to_le_bytes())?;
+        self.out.write_all(&(captured as u32).to_le_bytes())?;
+        self.out.write_all(&(datagram.len() as u32).to_le_bytes())?;
+        self.out.write_all(&datagram[..captured])?;
+        self.packets += 1;
+        Ok(())
+    }
+
+    // Flush everything to disk, returning how many packets were written
+    pub fn finish(mut self) -> io::Result<u64> {
+        self.out.flush()?;
+        Ok(self.packets)
+    }
+}
+
+// Magic number of a classic pcap file with microsecond timestamps
+const PCAP_MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+
+// pcapng block types, and the byte-order magic in the section header
+const PCAPNG_SECTION_HEADER: u32 = 0x0a0d_0d0a;
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 1;
+const PCAPNG_ENHANCED_PACKET: u32 = 6;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+
+// Interface description option giving the timestamp resolution
+const PCAPNG_OPTION_TSRESOL: u16 = 9;
+
+// Other link types we can find IPv4 datagrams in
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_LOOP: u32 = 108;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+// EtherTypes of IPv4 and of an 802.1Q VLAN tag
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+
+// Address family of IPv4 in NULL and LOOP headers, the same on every system that writes them
+const AF_INET: u8 = 2;
+
+// An IPv4 datagram read from a capture, with when it was captured in nanoseconds since the epoch
+pub struct CapturedPacket {
+    pub time_ns: u64,
+    pub datagram: Vec<u8>,
+}
+
+// Reads integers in whichever byte order the file was written in
+struct Reader<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl Reader<'_> {
+    fn u16(&self, offset: usize) -> Option<u16> {
+        let bytes: [u8; 2] = self.data.get(offset..offset + 2)?.try_into().ok()?;
+        Some(if self.big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+    }
+
+    fn u32(&self, offset: usize) -> Option<u32> {
+        let bytes: [u8; 4] = self.data.get(offset..offset + 4)?.try_into().ok()?;
+        Some(if self.big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) })
+    }
+}
+
+// The IPv4 datagram inside a captured frame, skipping whatever link-layer header it has
+fn ipv4_payload(link_type: u32, frame: &[u8]) -> Option<&[u8]> {
+    let ethertype = |offset: usize| frame.get(offset..offset + 2).map(|b| u16::from_be_bytes([b[0], b[1]]));
+    let datagram = match link_type {
+        LINKTYPE_RAW | LINKTYPE_IPV4 => frame,
+        // Address family in host byte order, or network byte order for LOOP
+        LINKTYPE_NULL | LINKTYPE_LOOP => {
+            let family = frame.get(..4)?;
+            if !family.contains(&AF_INET) {
+                return None;
+            }
+            &frame[4..]
+        },
+        LINKTYPE_ETHERNET => {
+            let offset = if ethertype(12)? == ETHERTYPE_VLAN { 16 } else { 12 };
+            if ethertype(offset)? != ETHERTYPE_IPV4 {
+                return None;
+            }
+            frame.get(offset + 2..)?
+        },
+        LINKTYPE_LINUX_SLL if ethertype(14)? == ETHERTYPE_IPV4 => frame.get(16..)?,
+        LINKTYPE_LINUX_SLL2 if ethertype(0)? == ETHERTYPE_IPV4 => frame.get(20..)?,
+        _ => return None,
+    };
+    (datagram.first()? >> 4 == 4).then_some(datagram)
+}
+
+// Read a classic pcap file
+fn read_pcap(data: &[u8]) -> Result<Vec<CapturedPacket>> {
+    let (big_endian, nanos) = match data.get(..4).map(|b| u32::from_le_bytes(b.try_into().unwrap())) {
+        Some(PCAP_MAGIC_MICROS) => (false, false),
+        Some(PCAP_MAGIC_NANOS) => (false, true),
+        Some(magic) if magic.swap_bytes() == PCAP_MAGIC_MICROS => (true, false),
+        Some(magic) if magic.swap_bytes() == PCAP_MAGIC_NANOS => (true, true),
+        _ => bail!("Not a pcap file"),
+    };
+    let reader = Reader { data, big_endian };
+    let link_type = reader.u32(20).ok_or_else(|| anyhow!("Truncated pcap header"))?;
+
+    let mut packets = Vec::new();
+    let mut offset = 24;
+    while offset < data.len() {
+        let (Some(seconds), Some(fraction), Some(captured)) =
+            (reader.u32(offset), reader.u32(offset + 4), reader.u32(offset + 8)) else {
+            bail!("Truncated pcap record at offset {}", offset);
+        };
+        let start = offset + 16;
+        let frame = data.get(start..start + captured as usize)
+            .ok_or_else(|| anyhow!("Truncated pcap record at offset {}", offset))?;
+        offset = start + captured as usize;
+
+        let fraction_ns = if nanos { fraction as u64 } else { fraction as u64 * 1000 };
+        if let Some(datagram) = ipv4_payload(link_type, frame) {
+            packets.push(CapturedPacket { time_ns: seconds as u64 * 1_000_000_000 + fraction_ns, datagram: datagram.to_vec() });
+        }
+    }
+    Ok(packets)
+}
+
+// Timestamp units per second from an interface's if_tsresol option, microseconds if it has none
+fn pcapng_resolution(reader: &Reader, mut offset: usize, end: usize) -> u128 {
+    while offset + 4 <= end {
+        let (Some(code), Some(length)) = (reader.u16(offset), reader.u16(offset + 2)) else {
+            break;
+        };
+        if code == PCAPNG_OPTION_TSRESOL && length >= 1 {
+            let Some(&resolution) = reader.data.get(offset + 4) else {
+                break;
+            };
+            let exponent = (resolution & 0x7f) as u32;
+            let base: u128 = if resolution & 0x80 == 0 { 10 } else { 2 };
+            return base.checked_pow(exponent).unwrap_or(1_000_000);
+        }
+        if code == 0 {
+            break;
+        }
+        offset += 4 + (length as usize).div_ceil(4) * 4;
+    }
+    1_000_000
+}
+
+// Read a pcapng file: enhanced packet blocks, timed by the interface they were captured on
+fn read_pcapng(data: &[u8]) -> Result<Vec<CapturedPacket>> {
+    let mut packets = Vec::new();
+    let mut reader = Reader { data, big_endian: false };
+    // Link type and timestamp resolution of each interface in the current section
+    let mut interfaces: Vec<(u32, u128)> = Vec::new();
+    let mut offset = 0;
+    while offset + 12 <= data.len() {
+        let block_type = reader.u32(offset).unwrap();
+        if block_type == PCAPNG_SECTION_HEADER {
+            // Each section sets its own byte order and interfaces
+            reader.big_endian = match reader.u32(offset + 8) {
+                Some(PCAPNG_BYTE_ORDER_MAGIC) => reader.big_endian,
+                Some(magic) if magic.swap_bytes() == PCAPNG_BYTE_ORDER_MAGIC => !reader.big_endian,
+                _ => bail!("Bad pcapng byte-order magic at offset {}", offset),
+            };
+            interfaces.clear();
+        }
+        let length = reader.u32(offset + 4).unwrap() as usize;
+        if length < 12 || offset + length > data.len() {
+            bail!("Truncated pcapng block at offset {}", offset);
+        }
+        let body = offset + 8;
+        let end = offset + length - 4;
+
+        match block_type {
+            PCAPNG_INTERFACE_DESCRIPTION => {
+                let link_type = reader.u16(body).unwrap_or(0) as u32;
+                interfaces.push((link_type, pcapng_resolution(&reader, body + 8, end)));
+            },
+            PCAPNG_ENHANCED_PACKET => {
+                let fields = (reader.u32(body), reader.u32(body + 4), reader.u32(body + 8), reader.u32(body + 12));
+                let (Some(interface), Some(high), Some(low), Some(captured)) = fields else {
+                    bail!("Truncated pcapng packet block at offset {}", offset);
+                };
+                let &(link_type, units_per_second) = interfaces.get(interface as usize)
+                    .ok_or_else(|| anyhow!("Packet block for unknown interface {}", interface))?;
+                let start = body + 20;
+                let frame = data.get(start..start + captured as usize)
+                    .filter(|_| start + captured as usize <= end)
+                    .ok_or_else(|| anyhow!("Truncated pcapng packet block at offset {}", offset))?;
+                let ticks = (high as u128) << 32 | low as u128;
+                let time_ns = (ticks * 1_000_000_000 / units_per_second) as u64;
+                if let Some(datagram) = ipv4_payload(link_type, frame) {
+                    packets.push(CapturedPacket { time_ns, datagram: datagram.to_vec() });
+                }
+            },
+            _ => {}
+        }
+        offset += length;
+    }
+    Ok(packets)
+}
+
+// Read the IPv4 datagrams out of a pcap or pcapng capture
+pub fn read_capture(path: &Path) -> Result<Vec<CapturedPacket>> {
+    let data = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let packets = if data.get(..4) == Some(&PCAPNG_SECTION_HEADER.to_le_bytes()[..]) {
+        read_pcapng(&data)
+    } else {
+        read_pcap(&data)
+    };
+    packets.with_context(|| format!("Failed to parse {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+    use std::time::Duration;
+
+    // A minimal IPv4 header followed by `fill`
+    fn datagram(len: usize, fill: u8) -> Vec<u8> {
+        let mut datagram = vec![fill; len];
+        datagram[0] = 0x45;
+        datagram
+    }
+
+    // A classic pcap file of raw IPv4 records, written in either byte order
+    fn classic_pcap(magic: u32, big_endian: bool, records: &[(u32, u32, &[u8])]) -> Vec<u8> {
+        let u16_bytes = |value: u16| if big_endian { value.to_be_bytes() } else { value.to_le_bytes() };
+        let u32_bytes = |value: u32| if big_endian { value.to_be_bytes() } else { value.to_le_bytes() };
+        let mut data = Vec::new();
+        data.extend(u32_bytes(magic));
+        data.extend(u16_bytes(PCAP_VERSION_MAJOR));
+        data.extend(u16_bytes(PCAP_VERSION_MINOR));
+        data.extend([0; 8]);
+        data.extend(u32_bytes(SNAPLEN));
+        data.extend(u32_bytes(LINKTYPE_RAW));
+        for &(seconds, fraction, frame) in records {
+            data.extend(u32_bytes(seconds));
+            data.extend(u32_bytes(fraction));
+            data.extend(u32_bytes(frame.len() as u32));
+            data.extend(u32_bytes(frame.len() as u32));
+            data.extend(frame);
+        }
+        data
+    }
+
+    #[test]
+    fn reads_back_what_the_writer_wrote() {
+        let path = env::temp_dir().join(format!("bingping-pcap-test-{}.pcap", process::id()));
+        let packets = [(UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789), datagram(84, 0xab)),
+                       (UNIX_EPOCH + Duration::new(1_700_000_001, 5), datagram(20, 0))];
+        let mut writer = PcapWriter::create(&path).unwrap();
+        for (time, datagram) in &packets {
+            writer.write(*time, datagram).unwrap();
+        }
+        assert_eq!(writer.finish().unwrap(), 2);
+
+        let read = read_capture(&path);
+        fs::remove_file(&path).unwrap();
+        let read = read.unwrap();
+        assert_eq!(read.len(), 2);
+        assert_eq!(read[0].time_ns, 1_700_000_000_123_456_789);
+        assert_eq!(read[1].time_ns, 1_700_000_001_000_000_005);
+        assert_eq!(read[0].datagram, packets[0].1);
+        assert_eq!(read[1].datagram, packets[1].1);
+    }
+
+    #[test]
+    fn reads_either_byte_order_and_resolution() {
+        let frame = datagram(28, 7);
+        for big_endian in [false, true] {
+            let nanos = read_pcap(&classic_pcap(PCAP_MAGIC_NANOS, big_endian, &[(10, 999_999_999, &frame)])).unwrap();
+            assert_eq!((nanos[0].time_ns, &nanos[0].datagram), (10_999_999_999, &frame), "big endian {}", big_endian);
+            let micros = read_pcap(&classic_pcap(PCAP_MAGIC_MICROS, big_endian, &[(10, 999_999, &frame)])).unwrap();
+            assert_eq!((micros[0].time_ns, &micros[0].datagram), (10_999_999_000, &frame), "big endian {}", big_endian);
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let frame = datagram(28, 7);
+        let data = classic_pcap(PCAP_MAGIC_NANOS, false, &[(1, 0, &frame)]);
+        assert!(read_pcap(&data[..data.len() - 1]).is_err());
+        assert!(read_pcap(&data[..20]).is_err());
+        assert!(read_pcap(b"not a capture").is_err());
+    }
+
+    #[test]
+    fn strips_each_link_layer_header() {
+        let ip = datagram(28, 3);
+        let framed = |header: &[u8]| [header, &ip].concat();
+
+        assert_eq!(ipv4_payload(LINKTYPE_RAW, &ip), Some(&ip[..]));
+        assert_eq!(ipv4_payload(LINKTYPE_IPV4, &ip), Some(&ip[..]));
+        // AF_INET in either byte order
+        assert_eq!(ipv4_payload(LINKTYPE_NULL, &framed(&[AF_INET, 0, 0, 0])), Some(&ip[..]));
+        assert_eq!(ipv4_payload(LINKTYPE_LOOP, &framed(&[0, 0, 0, AF_INET])), Some(&ip[..]));
+        assert_eq!(ipv4_payload(LINKTYPE_NULL, &framed(&[30, 0, 0, 0])), None);
+
+        let mut ethernet = [0u8; 14];
+        ethernet[12..].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        assert_eq!(ipv4_payload(LINKTYPE_ETHERNET, &framed(&ethernet)), Some(&ip[..]));
+        let mut vlan = [0u8; 18];
+        vlan[12..14].copy_from_slice(&ETHERTYPE_VLAN.to_be_bytes());
+        vlan[16..].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        assert_eq!(ipv4_payload(LINKTYPE_ETHERNET, &framed(&vlan)), Some(&ip[..]));
+        // IPv6
+        ethernet[12..].copy_from_slice(&0x86ddu16.to_be_bytes());
+        assert_eq!(ipv4_payload(LINKTYPE_ETHERNET, &framed(&ethernet)), None);
+
+        let mut sll = [0u8; 16];
+        sll[14..].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        assert_eq!(ipv4_payload(LINKTYPE_LINUX_SLL, &framed(&sll)), Some(&ip[..]));
+        let mut sll2 = [0u8; 20];
+        sll2[..2].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        assert_eq!(ipv4_payload(LINKTYPE_LINUX_SLL2, &framed(&sll2)), Some(&ip[..]));
+
+        // Unknown link types, and frames that claim IPv4 but don't hold it
+        assert_eq!(ipv4_payload(147, &ip), None);
+        assert_eq!(ipv4_payload(LINKTYPE_RAW, &datagram(28, 3)[1..]), None);
+        assert_eq!(ipv4_payload(LINKTYPE_ETHERNET, &ethernet[..13]), None);
+    }
+}
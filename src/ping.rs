@@ -1,24 +1,63 @@
-use std::io;
 use std::time::{Duration, Instant};
-use std::thread;
-use std::sync::{Arc, Mutex};
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::process::Command;
+#[cfg(not(target_os = "linux"))]
+use std::thread;
 
-use pnet::packet::icmp::{IcmpTypes, MutableIcmpPacket};
-use pnet::packet::Packet;
-use pnet_transport::{transport_channel, TransportChannelType};
-use pnet_transport::TransportProtocol::Ipv4;
-use pnet_transport::icmp_packet_iter;
 use anyhow::{Context, Result, anyhow};
-use crossbeam_channel::{select, tick};
-use rand::random;
 
 use crate::config::PingConfig;
+use crate::packet::IpOption;
 use crate::stats::PingStats;
-use crate::packet::create_icmp_packet;
-use crate::display::{rainbow_text, pink_text};
+
+// Everything below is for the raw-socket implementation, which is Linux-only
+#[cfg(target_os = "linux")]
+use std::{fmt, io};
+#[cfg(target_os = "linux")]
+use std::collections::{BTreeMap, HashMap, HashSet};
+#[cfg(target_os = "linux")]
+use std::net::{IpAddr, Ipv4Addr};
+#[cfg(target_os = "linux")]
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(target_os = "linux")]
+use std::path::Path;
+#[cfg(target_os = "linux")]
+use std::time::SystemTime;
+
+#[cfg(target_os = "linux")]
+use anyhow::bail;
+#[cfg(target_os = "linux")]
+use pnet::packet::icmp::{IcmpPacket, IcmpTypes};
+#[cfg(target_os = "linux")]
+use pnet::packet::ipv4::Ipv4Packet;
+#[cfg(target_os = "linux")]
+use pnet::packet::Packet;
+#[cfg(target_os = "linux")]
+use rand::random;
+
+#[cfg(target_os = "linux")]
+use crate::config::PingMode;
+#[cfg(target_os = "linux")]
+use crate::stats::linear_regression;
+#[cfg(target_os = "linux")]
+use crate::packet::{ICMP_HEADER_LEN, ICMP_TIMESTAMP_LEN, IpTimestampMode, RecordedOptions, create_icmp_packet,
+                    create_timestamp_packet, dscp_name, ms_since_midnight_utc, parse_ip_options, parse_timestamp_reply,
+                    split_echo_payload, stamp_echo_packet, timestamp_diff, build_ipv4_datagram, data_mismatches,
+                    echo_art_data, echo_art_start, icmp_checksum_ok};
+#[cfg(target_os = "linux")]
+use crate::art::{chunk_offset, chunk_size, describe_ranges, parse_art_chunk, write_art_chunk, ArtReassembly, ART_CHUNK_START};
+#[cfg(target_os = "linux")]
+use crate::display::{art_width, sanitize_art, style_art};
+#[cfg(target_os = "linux")]
+use crate::pcap::PcapWriter;
+#[cfg(target_os = "linux")]
+use crate::simulate::{SimulatedTransport, SimulationSpec};
+#[cfg(target_os = "linux")]
+use crate::socket::{monotonic_to_wall, route_source, wait_readable, wall_to_monotonic_ns, KernelTimestamps,
+                    PmtuDiscovery, SourceBinding, Waker};
+#[cfg(target_os = "linux")]
+use crate::transport::{ProbeTransport, RawSocketTransport};
 
 // Execute a system ping command (fallback if raw sockets not available)
 pub fn execute_system_ping(config: &PingConfig) -> Result<()> {
@@ -43,6 +82,42 @@ pub fn execute_system_ping(config: &PingConfig) -> Result<()> {
         cmd.args(["-W", &timeout.to_string()]);
         
         cmd.args(["-t", &config.ttl.to_string()]);
+        
+        if let Some(tos) = config.tos {
+            cmd.args(["-Q", &tos.to_string()]);
+        }
+        
+        if config.bypass_route {
+            cmd.arg("-r");
+        }
+        
+        if config.broadcast {
+            cmd.arg("-b");
+        }
+        
+        if let Some(source) = &config.source {
+            cmd.args(["-I", &source.to_string()]);
+        }
+        
+        if let Some(mark) = config.mark {
+            cmd.args(["-m", &mark.to_string()]);
+        }
+        
+        match config.ip_option {
+            Some(IpOption::RecordRoute) => { cmd.arg("-R"); },
+            Some(IpOption::Timestamp(IpTimestampMode::Tsonly)) => { cmd.args(["-T", "tsonly"]); },
+            Some(IpOption::Timestamp(IpTimestampMode::Tsandaddr)) => { cmd.args(["-T", "tsandaddr"]); },
+            None => {}
+        }
+        
+        if let Some(mode) = config.pmtu_discovery {
+            let mode = match mode {
+                PmtuDiscovery::Do => "do",
+                PmtuDiscovery::Want => "want",
+                PmtuDiscovery::Dont => "dont",
+            };
+            cmd.args(["-M", mode]);
+        }
     }
     
     #[cfg(target_os = "windows")]
@@ -60,6 +135,13 @@ pub fn execute_system_ping(config: &PingConfig) -> Result<()> {
         cmd.args(["-w", &timeout.to_string()]);
         
         cmd.args(["-i", &config.ttl.to_string()]);
+        
+        // Windows ping takes a hop count for each option, so ask for as many as the option has room for
+        match config.ip_option {
+            Some(IpOption::RecordRoute) => { cmd.args(["-r", "9"]); },
+            Some(IpOption::Timestamp(_)) => { cmd.args(["-s", "4"]); },
+            None => {}
+        }
     }
     
     // Execute the ping command and stream output
@@ -73,246 +155,915 @@ pub fn execute_system_ping(config: &PingConfig) -> Result<()> {
     }
 }
 
-// Ping implementation using raw sockets
-pub fn ping_with_raw_sockets(config: &PingConfig) -> Result<()> {
-    let ip_addr = config.ip_addr;
-    let packet_size = config.packet_size;
-    let ttl = config.ttl;
-    let destination = config.destination.clone();
-    
-    // Generate random identifier
-    let identifier = (random::<u16>() % 65535) as u16;
-    
-    println!("Using ICMP identifier: {}", identifier);
-    
-    // Create transport channel for ICMP
-    // Protocol 1 is ICMP for IPv4
-    let protocol = Ipv4(pnet::packet::ip::IpNextHeaderProtocol(1));
-    println!("Creating transport channel for ICMP (protocol number: 1)");
+// Running flag cleared on CTRL+C, with an eventfd on Linux so waits end straight away
+pub struct Shutdown {
+    running: AtomicBool,
+    #[cfg(target_os = "linux")]
+    waker: Waker,
+}
+
+// How often sleeps check for CTRL+C where there's no eventfd to wake them
+#[cfg(not(target_os = "linux"))]
+const SHUTDOWN_POLL: Duration = Duration::from_millis(50);
+
+impl Shutdown {
+    pub fn new() -> Result<Arc<Self>> {
+        Ok(Arc::new(Shutdown {
+            running: AtomicBool::new(true),
+            #[cfg(target_os = "linux")]
+            waker: Waker::new().context("Failed to create eventfd")?,
+        }))
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    fn stop(&self) {
+        self.running.store(false, Ordering::SeqCst);
+        #[cfg(target_os = "linux")]
+        self.waker.wake();
+    }
+
+    // Descriptor that becomes readable once stopped, for poll loops
+    #[cfg(target_os = "linux")]
+    pub fn fd(&self) -> RawFd {
+        self.waker.as_raw_fd()
+    }
+
+    // Sleep for up to `duration`, returning early on CTRL+C
+    #[cfg(target_os = "linux")]
+    pub fn sleep(&self, duration: Duration) {
+        let _ = wait_readable(&[self.fd()], Some(duration));
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn sleep(&self, duration: Duration) {
+        let deadline = Instant::now() + duration;
+        while self.is_running() {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            thread::sleep((deadline - now).min(SHUTDOWN_POLL));
+        }
+    }
+}
+
+// Create the running flag and clear it when CTRL+C is pressed
+pub fn install_ctrlc_handler() -> Result<Arc<Shutdown>> {
+    let shutdown = Shutdown::new()?;
+    let shutdown_ctrlc = Arc::clone(&shutdown);
+    ctrlc::set_handler(move || shutdown_ctrlc.stop()).context("Failed to set CTRL+C handler")?;
+    Ok(shutdown)
+}
+
+// Print the summary shown at the end of every ping run
+pub fn print_statistics(destination: &str, stats: &PingStats, elapsed: Duration) {
+    println!("\n--- {} ping statistics ---", destination);
+    let duplicates = if stats.duplicates > 0 {
+        format!(", +{} duplicates", stats.duplicates)
+    } else {
+        String::new()
+    };
+    let corrupted = if stats.corrupted > 0 {
+        format!(", +{} corrupted", stats.corrupted)
+    } else {
+        String::new()
+    };
+    println!("{} packets transmitted, {} received{}{}, {:.1}% packet loss, time {:.0}ms",
+             stats.packets_sent, stats.packets_received, duplicates, corrupted, stats.packet_loss(),
+             elapsed.as_secs_f64() * 1000.0);
     
-    let (mut tx, mut rx) = match transport_channel(4096, TransportChannelType::Layer4(protocol)) {
-        Ok((tx, rx)) => {
-            println!("Successfully created transport channel");
-            (tx, rx)
-        },
-        Err(e) => {
-            eprintln!("Error creating transport channel: {}", e);
-            eprintln!("This is likely due to permissions - try running with sudo or as administrator");
-            return Err(anyhow!("Failed to create ICMP socket: {}", e));
+    if stats.packets_received > 0 {
+        println!("rtt min/avg/max = {:.3}/{:.3}/{:.3} ms, jitter {:.3} ms",
+                 stats.rtt_min, stats.avg_rtt(), stats.rtt_max, stats.jitter());
+    }
+}
+
+// Report echo data that came back different, the first wrong byte like iputils does and then
+// every offset that differs
+#[cfg(target_os = "linux")]
+fn print_data_mismatches(expected: &[u8], received: &[u8], wrong: &[usize]) {
+    let Some(&first) = wrong.first() else {
+        return;
+    };
+    match (expected.get(first), received.get(first)) {
+        (Some(sent), Some(got)) => println!("  - wrong data byte #{} should be 0x{:02x} but was 0x{:02x}", first, sent, got),
+        _ => println!("  - reply carried {} bytes of data, sent {}", received.len(), expected.len()),
+    }
+    if wrong.len() > 1 {
+        println!("  - {} bytes differ: #{}", wrong.len(), describe_ranges(wrong));
+    }
+}
+
+// A kernel transmit timestamp only belongs to a send time this close before it
+#[cfg(target_os = "linux")]
+const MAX_TX_DELAY_NS: u64 = 1_000_000_000;
+
+// Per-sequence times in monotonic nanoseconds. Zero marks an empty slot.
+#[cfg(target_os = "linux")]
+struct SeqTimes(Vec<u64>);
+
+#[cfg(target_os = "linux")]
+impl SeqTimes {
+    fn new() -> Self {
+        SeqTimes(vec![0; u16::MAX as usize + 1])
+    }
+
+    fn set(&mut self, seq: u16, ns: u64) {
+        self.0[seq as usize] = ns;
+    }
+
+    fn get(&self, seq: u16) -> Option<u64> {
+        Some(self.0[seq as usize]).filter(|&ns| ns != 0)
+    }
+}
+
+// Which clocks a round-trip time was measured with
+#[cfg(target_os = "linux")]
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ClockSource {
+    // Kernel transmit and receive timestamps
+    Kernel,
+    // Kernel receive timestamp, userspace send time
+    KernelRx,
+    // Userspace times on both ends
+    User,
+}
+
+#[cfg(target_os = "linux")]
+impl fmt::Display for ClockSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClockSource::Kernel => write!(f, "kernel"),
+            ClockSource::KernelRx => write!(f, "kernel-rx"),
+            ClockSource::User => write!(f, "user"),
+        }
+    }
+}
+
+// Round-trip time in milliseconds from the most precise pair of timestamps available. All times
+// are monotonic nanoseconds; `sent` is the userspace send time carried by the probe.
+#[cfg(target_os = "linux")]
+fn round_trip(sent: u64, kernel_tx: Option<u64>, user_rx: u64, kernel_rx: Option<u64>) -> (f64, ClockSource) {
+    let elapsed = |from: u64, to: u64| to.checked_sub(from).map(|ns| ns as f64 / 1_000_000.0);
+    // Ignore a transmit timestamp left over from an earlier probe with the same sequence
+    let kernel_tx = kernel_tx.filter(|&tx| tx >= sent && tx - sent < MAX_TX_DELAY_NS);
+    // Fall back to fewer kernel times when they're missing or out of order, as happens when a
+    // local reply overtakes the transmit timestamp
+    kernel_tx.zip(kernel_rx)
+        .and_then(|(tx, rx)| elapsed(tx, rx))
+        .map(|rtt| (rtt, ClockSource::Kernel))
+        .or_else(|| kernel_rx.and_then(|rx| elapsed(sent, rx)).map(|rtt| (rtt, ClockSource::KernelRx)))
+        .unwrap_or_else(|| (elapsed(sent, user_rx).unwrap_or(0.0), ClockSource::User))
+}
+
+// Describe the timestamps the socket gives us, for the header
+#[cfg(target_os = "linux")]
+fn describe_timestamps(timestamps: KernelTimestamps) -> &'static str {
+    match (timestamps.rx, timestamps.tx) {
+        (true, true) => "kernel RX/TX",
+        (true, false) => "kernel RX, userspace TX",
+        _ => "userspace",
+    }
+}
+
+// How a reply relates to earlier replies to the same probe
+#[cfg(target_os = "linux")]
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ReplyKind {
+    // The first answer to this probe from anyone
+    First,
+    // The first answer from this host, after another host already answered
+    OtherResponder,
+    // This host already answered this probe
+    Duplicate,
+}
+
+#[cfg(target_os = "linux")]
+impl ReplyKind {
+    fn suffix(self) -> &'static str {
+        match self {
+            ReplyKind::Duplicate => " (DUP!)",
+            _ => "",
+        }
+    }
+}
+
+// Note for replies that arrive after the timeout, which are still timed from the send time they carry
+#[cfg(target_os = "linux")]
+fn late_note(rtt_ns: u64, timeout_ns: u64) -> &'static str {
+    if rtt_ns > timeout_ns {
+        " (late)"
+    } else {
+        ""
+    }
+}
+
+// Who has answered a probe so far and when it was sent, kept in the ReplyHandler on the event loop
+#[cfg(target_os = "linux")]
+struct ProbeReplies {
+    sent_ns: u64,
+    responders: HashSet<IpAddr>,
+}
+
+// Note who answered a probe and how the reply relates to earlier ones. Replies are kept per
+// sequence until the sequence is reused by a later probe, so a broadcast probe can be matched
+// against replies from many hosts and late duplicates are still recognised.
+#[cfg(target_os = "linux")]
+fn classify_reply(replies: &mut HashMap<u16, ProbeReplies>, seq: u16, sent_ns: u64, from: IpAddr) -> ReplyKind {
+    let probe = replies.entry(seq).or_insert_with(|| ProbeReplies { sent_ns, responders: HashSet::new() });
+    if probe.sent_ns != sent_ns {
+        *probe = ProbeReplies { sent_ns, responders: HashSet::new() };
+    }
+    let kind = if probe.responders.is_empty() {
+        ReplyKind::First
+    } else if probe.responders.contains(&from) {
+        ReplyKind::Duplicate
+    } else {
+        ReplyKind::OtherResponder
+    };
+    probe.responders.insert(from);
+    kind
+}
+
+// Count a reply in the overall and per-responder statistics
+#[cfg(target_os = "linux")]
+fn record_reply(stats: &mut PingStats, responder_stats: &mut BTreeMap<IpAddr, PingStats>,
+                kind: ReplyKind, from: IpAddr, rtt: f64) {
+    match kind {
+        ReplyKind::First => stats.update(rtt),
+        ReplyKind::OtherResponder => {}
+        ReplyKind::Duplicate => {
+            stats.duplicates += 1;
+            return;
         }
+    }
+    responder_stats.entry(from).or_insert_with(PingStats::new).update(rtt);
+}
+
+// Print a line per host that answered, for broadcast and multicast pings
+#[cfg(target_os = "linux")]
+fn print_responders(responder_stats: &BTreeMap<IpAddr, PingStats>, packets_sent: u64) {
+    println!("\n--- {} responders ---", responder_stats.len());
+    for (addr, stats) in responder_stats {
+        println!("{}: {}/{} replies, rtt min/avg/max = {:.3}/{:.3}/{:.3} ms",
+                 addr, stats.packets_received, packets_sent, stats.rtt_min, stats.avg_rtt(), stats.rtt_max);
+    }
+}
+
+// Describe the TOS byte of a reply when probes were marked, flagging any remarking on the path
+#[cfg(target_os = "linux")]
+fn tos_note(sent_tos: Option<u8>, reply_tos: u8) -> String {
+    let Some(sent_tos) = sent_tos else {
+        return String::new();
     };
+    let mut note = format!(" tos=0x{:02x} (dscp {}, ecn {})", reply_tos, dscp_name(reply_tos >> 2), reply_tos & 0x03);
+    if reply_tos != sent_tos {
+        note.push_str(&format!(" remarked from 0x{:02x}", sent_tos));
+    }
+    note
+}
+
+// Print the route or timestamps recorded by the IP options of a reply
+#[cfg(target_os = "linux")]
+fn print_recorded_options(recorded: &RecordedOptions) {
+    if recorded.is_empty() {
+        return;
+    }
+    for (i, hop) in recorded.route.iter().enumerate() {
+        println!("{}\t{}", if i == 0 { "RR:" } else { "" }, hop);
+    }
+    for (i, (hop, time)) in recorded.timestamps.iter().enumerate() {
+        let label = if i == 0 { "TS:" } else { "" };
+        match hop {
+            Some(hop) => println!("{}\t{}\t{} absolute", label, hop, time),
+            None => println!("{}\t{} absolute", label, time),
+        }
+    }
+    if recorded.timestamp_overflow > 0 {
+        println!("\t({} hops not recorded)", recorded.timestamp_overflow);
+    }
+}
+
+// Print per-size statistics and the fit of RTT against packet size
+#[cfg(target_os = "linux")]
+fn print_size_sweep(size_stats: &BTreeMap<usize, PingStats>) {
+    println!("\n--- packet size sweep ---");
+    println!("{:>8} {:>6} {:>6} {:>7} {:>10} {:>10} {:>10}", "bytes", "sent", "recv", "loss", "min", "avg", "max");
+    for (size, stats) in size_stats {
+        if stats.packets_received > 0 {
+            println!("{:>8} {:>6} {:>6} {:>6.1}% {:>10.3} {:>10.3} {:>10.3}",
+                     size, stats.packets_sent, stats.packets_received, stats.packet_loss(),
+                     stats.rtt_min, stats.avg_rtt(), stats.rtt_max);
+        } else {
+            println!("{:>8} {:>6} {:>6} {:>6.1}% {:>10} {:>10} {:>10}",
+                     size, stats.packets_sent, stats.packets_received, stats.packet_loss(), "-", "-", "-");
+        }
+    }
     
-    // Statistics
-    let stats = Arc::new(Mutex::new(PingStats::new()));
-    let stats_clone = Arc::clone(&stats);
+    // Fit the average RTT of each size that got replies
+    let points: Vec<(f64, f64)> = size_stats.iter()
+        .filter(|(_, stats)| stats.packets_received > 0)
+        .map(|(&size, stats)| (size as f64, stats.avg_rtt()))
+        .collect();
+    match linear_regression(&points) {
+        Some((slope, intercept)) => {
+            println!("rtt = {:.3} ms + {:.3} us/byte", intercept, slope * 1000.0);
+            // The payload crosses the bottleneck twice, once in each direction
+            if slope > 0.0 {
+                let bandwidth_mbps = 2.0 * 8.0 / (slope / 1000.0) / 1_000_000.0;
+                println!("estimated bottleneck bandwidth {:.1} Mbit/s", bandwidth_mbps);
+            } else {
+                println!("rtt does not grow with size, bottleneck bandwidth not measurable");
+            }
+        },
+        None => println!("not enough sizes with replies to fit rtt against size"),
+    }
+}
+
+// Run request/response probes one at a time, honouring count, interval and CTRL+C.
+// The probe prints its own reply line and returns the round-trip time in milliseconds;
+// an error counts as a lost probe.
+pub fn run_probe_loop<F>(config: &PingConfig, mut probe: F) -> Result<()>
+where
+    F: FnMut(u16) -> Result<f64>,
+{
+    let shutdown = install_ctrlc_handler()?;
+    let mut stats = PingStats::new();
+    let start_time = Instant::now();
+    let interval = Duration::from_millis(config.interval_ms);
+    let mut sequence: u16 = 0;
     
-    // Running flag
-    let running = Arc::new(AtomicBool::new(true));
-    let running_clone = Arc::clone(&running);
+    while shutdown.is_running() {
+        let probe_start = Instant::now();
+        
+        stats.packets_sent += 1;
+        match probe(sequence) {
+            Ok(rtt) => stats.update(rtt),
+            Err(e) => println!("From {}: seq={} {:#}", config.destination, sequence, e),
+        }
+        sequence = sequence.wrapping_add(1);
+        
+        // Check if we've sent enough probes
+        if let Some(count) = config.count {
+            if stats.packets_sent >= count as u64 {
+                break;
+            }
+        }
+        
+        // Wait out the rest of the interval, waking early on CTRL+C
+        shutdown.sleep(interval.saturating_sub(probe_start.elapsed()));
+    }
     
-    // Set up CTRL+C handler
-    let running_ctrlc = Arc::clone(&running);
-    ctrlc::set_handler(move || {
-        running_ctrlc.store(false, Ordering::SeqCst);
-    }).context("Failed to set CTRL+C handler")?;
+    print_statistics(&config.destination, &stats, start_time.elapsed());
+    
+    Ok(())
+}
+
+// Source address of our probes: the bound address, or whichever one the route to the destination uses
+#[cfg(target_os = "linux")]
+fn probe_source(config: &PingConfig) -> Ipv4Addr {
+    match (&config.source, route_source(config.ip_addr)) {
+        (Some(SourceBinding::Address(IpAddr::V4(ip))), _) => *ip,
+        (_, Ok(IpAddr::V4(ip))) => ip,
+        _ => Ipv4Addr::UNSPECIFIED,
+    }
+}
+
+// Probes and replies written to a pcap file. Probes are captured before the kernel puts an IPv4
+// header on them, so one is built for them from the socket settings.
+#[cfg(target_os = "linux")]
+pub struct Capture {
+    writer: PcapWriter,
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    ttl: u8,
+    tos: u8,
+    options: Vec<u8>,
+}
+
+#[cfg(target_os = "linux")]
+impl Capture {
+    fn open(config: &PingConfig, path: &Path) -> Result<Self> {
+        let IpAddr::V4(destination) = config.ip_addr else {
+            bail!("Packet capture is only supported for IPv4 destinations");
+        };
+        let writer = PcapWriter::create(path)
+            .with_context(|| format!("Failed to create capture file {}", path.display()))?;
+        Ok(Capture {
+            writer,
+            source: probe_source(config),
+            destination,
+            ttl: config.ttl,
+            tos: config.tos.unwrap_or(0),
+            options: config.ip_option.map(|option| option.to_bytes()).unwrap_or_default(),
+        })
+    }
+
+    // Capture an ICMP message we sent
+    fn probe(&mut self, time: SystemTime, message: &[u8]) -> io::Result<()> {
+        let datagram = build_ipv4_datagram(self.source, self.destination, self.ttl, self.tos, &self.options, message);
+        self.writer.write(time, &datagram)
+    }
+}
+
+// Matches replies to our probes and reports them, owned by the event loop
+#[cfg(target_os = "linux")]
+struct ReplyHandler {
+    identifier: u16,
+    packet_sizes: Vec<usize>,
+    sweeping: bool,
+    rainbow: bool,
+    sent_tos: Option<u8>,
+    kernel_tx: bool,
+    timeout_ns: u64,
+    // Echo probes carry their own send time; probes without room for it, and kernel transmit
+    // timestamps, are kept per sequence number
+    sent_times: SeqTimes,
+    tx_times: SeqTimes,
+    // Who has answered each probe, for duplicate and broadcast handling
+    replies: HashMap<u16, ProbeReplies>,
+    // Statistics, overall, per packet size when sweeping sizes, and per responder for when one
+    // probe can be answered by many hosts
+    stats: PingStats,
+    size_stats: BTreeMap<usize, PingStats>,
+    responder_stats: BTreeMap<IpAddr, PingStats>,
+    // Where probes and replies are captured, if anywhere
+    capture: Option<Capture>,
+    // The art probes carry, and the chunk of it each probe carried in chunked mode, so replies
+    // can be checked against what was sent
+    art: Vec<u8>,
+    chunk_offsets: HashMap<u16, usize>,
+    // Art being put back together from chunked replies
+    art_chunks: Option<ArtReassembly>,
+}
+
+#[cfg(target_os = "linux")]
+impl ReplyHandler {
+    // Write to the capture file, giving up on capturing if that fails
+    fn capture<F>(&mut self, write: F)
+    where
+        F: FnOnce(&mut Capture) -> io::Result<()>,
+    {
+        if let Some(capture) = &mut self.capture {
+            if let Err(e) = write(capture) {
+                eprintln!("Failed to write to the pcap file, no longer capturing: {}", e);
+                self.capture = None;
+            }
+        }
+    }
+
+    // Show art a reply carried, unless it's only padding (some servers just send zeros)
+    fn show_art(&self, heading: &str, art: &[u8]) {
+        // Convert to string, ignoring non-printable characters
+        let art_string = sanitize_art(art);
+        
+        if !art_string.trim().is_empty() {
+            println!("{}", heading);
+            println!("{}", style_art(&art_string, self.rainbow));
+        }
+    }
     
-    // Create sequence number counter
-    let sequence = Arc::new(AtomicU64::new(0));
+    // Show the art in a reply, or file it towards the whole picture if it's a chunk
+    fn receive_art(&mut self, art_data: &[u8]) {
+        if let (Some(reassembly), Some(chunk)) = (&mut self.art_chunks, parse_art_chunk(art_data)) {
+            let Some(index) = reassembly.add(&chunk) else {
+                return;
+            };
+            println!("  - Art chunk {}/{} at offset {}", index + 1, reassembly.chunk_count(), chunk.offset);
+            if let Some(art) = reassembly.complete().filter(|_| !reassembly.rendered) {
+                reassembly.rendered = true;
+                let chunks = reassembly.chunk_count();
+                let art = sanitize_art(&art);
+                let heading = format!("Received complete ASCII art in {} chunks, {} columns by {} lines:",
+                                      chunks, art_width(&art), art.lines().count());
+                self.show_art(&heading, art.as_bytes());
+            }
+            return;
+        }
+        self.show_art("Received ASCII art in reply:", art_data);
+    }
     
-    // Map to store send timestamps for each sequence number
-    let send_times = Arc::new(Mutex::new(HashMap::new()));
-    let send_times_clone = Arc::clone(&send_times);
+    // The echo data probe `seq` carried, taking the send time from the reply since it isn't kept
+    fn expected_data(&self, seq: u16, received: &[u8]) -> Vec<u8> {
+        let size = self.size_for_seq(seq);
+        let art_start = echo_art_start(size);
+        let mut expected = vec![0u8; art_start];
+        let stamp_len = art_start.min(received.len());
+        expected[..stamp_len].copy_from_slice(&received[..stamp_len]);
+        match self.chunk_offsets.get(&seq) {
+            Some(&offset) => {
+                expected.resize(size, 0);
+                write_art_chunk(&mut expected[art_start..], &self.art, offset);
+            },
+            None => expected.extend(echo_art_data(&self.art, size - art_start)),
+        }
+        expected
+    }
     
-    // Pass a copy of the config to the receiver thread
-    let rainbow = config.rainbow;
+    // Offsets where a reply's echo data differs from what its probe carried
+    fn verify_data(&self, seq: u16, payload: &[u8]) -> (Vec<u8>, Vec<usize>) {
+        let received = &payload[4..];
+        let expected = self.expected_data(seq, received);
+        let wrong = data_mismatches(&expected, received);
+        (expected, wrong)
+    }
     
-    // Print header
-    println!("PING {} ({}) {} bytes of data.", 
-             destination, ip_addr, packet_size);
+    // Whether echo data is a chunk of some other art, as a server answering with its own sends back.
+    // Only the chunk header gives that away; any other difference is the data being damaged.
+    fn is_foreign_art(&self, art_data: &[u8]) -> bool {
+        self.art_chunks.is_some() && parse_art_chunk(art_data).is_some_and(|chunk| chunk.total != self.art.len())
+    }
     
-    // Launch receiver thread
-    let receiver_thread = thread::spawn(move || {
-        let mut icmp_iter = icmp_packet_iter(&mut rx);
-        
-        println!("Receiver thread started, waiting for packets...");
-        
-        while running_clone.load(Ordering::Relaxed) {
-            // Create a ticker for heartbeat
-            let ticker = tick(Duration::from_secs(1));
-            
-            select! {
-                recv(ticker) -> _ => {
-                    // Just a heartbeat to check for exit flag
+    // Sizes cycle with the sequence number, so a reply tells us which size its probe used
+    fn size_for_seq(&self, seq: u16) -> usize {
+        self.packet_sizes[seq as usize % self.packet_sizes.len()]
+    }
+
+    // File transmit timestamps that have come in under the probes they're for
+    fn collect_tx_timestamps(&mut self, transport: &mut dyn ProbeTransport) {
+        for (seq, ns) in transport.tx_timestamps() {
+            self.tx_times.set(seq, ns);
+        }
+    }
+
+    // Read everything the transport has waiting
+    fn drain(&mut self, transport: &mut dyn ProbeTransport, recv_buffer: &mut [u8]) {
+        // Transmit timestamps first, so the replies to those probes can use them
+        if self.kernel_tx {
+            self.collect_tx_timestamps(transport);
+        }
+        loop {
+            match transport.recv(recv_buffer) {
+                Ok(received) => {
+                    let recv_time = transport.now();
+                    let recv_kernel = received.kernel_time.map(wall_to_monotonic_ns);
+                    let captured_at = received.kernel_time.unwrap_or_else(|| monotonic_to_wall(recv_time));
+                    self.capture(|capture| capture.writer.write(captured_at, &recv_buffer[..received.len]));
+                    self.handle(transport, &recv_buffer[..received.len], received.from, recv_time, recv_kernel);
                 },
-                default => {
-                    // Try to receive a packet with a non-blocking approach
-                    match icmp_iter.next() {
-                        Ok((packet, addr)) => {
-                            let recv_time = Instant::now();
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    eprintln!("Error receiving packet: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    // Round-trip time of a reply, picking up a transmit timestamp the reply may have overtaken
+    fn round_trip(&mut self, transport: &mut dyn ProbeTransport, seq: u16, sent: u64, recv_time: u64,
+                  recv_kernel: Option<u64>) -> (f64, ClockSource) {
+        if self.kernel_tx && self.tx_times.get(seq).is_none() {
+            self.collect_tx_timestamps(transport);
+        }
+        round_trip(sent, self.tx_times.get(seq), recv_time, recv_kernel)
+    }
+
+    // Match a whole IPv4 datagram against our probes and report it
+    fn handle(&mut self, transport: &mut dyn ProbeTransport, datagram: &[u8], addr: IpAddr, recv_time: u64, recv_kernel: Option<u64>) {
+        let identifier = self.identifier;
+
+        // Split the datagram into the IPv4 header and the ICMP message
+        let Some(ip_packet) = Ipv4Packet::new(datagram) else {
+            return;
+        };
+        let header_len = ip_packet.get_header_length() as usize * 4;
+        let Some(packet) = datagram.get(header_len..).and_then(IcmpPacket::new) else {
+            return;
+        };
+        let reply_ttl = ip_packet.get_ttl();
+        let reply_tos = tos_note(self.sent_tos, ip_packet.get_dscp() << 2 | ip_packet.get_ecn());
+        let recorded = parse_ip_options(&datagram[20.min(header_len)..header_len]);
+        let checksum_note = if icmp_checksum_ok(&packet) { "" } else { " (BAD CHECKSUM!)" };
+
+        // Print packet type for debugging
+        println!("Received ICMP packet type: {:?} from {}", packet.get_icmp_type(), addr);
+
+        if packet.get_icmp_type() == IcmpTypes::EchoReply {
+            // Extract the payload which should contain our identifier and sequence
+            let payload = packet.payload();
+            if payload.len() >= 4 { // Need at least 4 bytes for ID and seq
+                let reply_id = ((payload[0] as u16) << 8) | (payload[1] as u16);
+                let reply_seq = ((payload[2] as u16) << 8) | (payload[3] as u16);
+                
+                println!("  - Packet ID: {}, Sequence: {}, Expected ID: {}", 
+                         reply_id, reply_seq, identifier);
+                
+                if reply_id == identifier {
+                    // Take the send time from the payload, or from the sender if it didn't fit
+                    let (payload_sent, art_data) = split_echo_payload(payload);
+                    let sent = payload_sent
+                        .filter(|&sent| sent != 0 && sent <= recv_time)
+                        .or_else(|| self.sent_times.get(reply_seq));
+                    if let Some(sent) = sent {
+                        let (rtt, clock) = self.round_trip(transport, reply_seq, sent, recv_time, recv_kernel);
+                        let kind = classify_reply(&mut self.replies, reply_seq, sent, addr);
+
+                        let packet_size = self.size_for_seq(reply_seq);
+                        println!("{} bytes from {}: icmp_seq={} ttl={}{} time={:.3} ms clock={}{}{}{}",
+                                packet_size, addr, reply_seq, reply_ttl, reply_tos, rtt, clock,
+                                late_note(recv_time - sent, self.timeout_ns), kind.suffix(), checksum_note);
+                        print_recorded_options(&recorded);
+                        
+                        // Check the data came back as it was sent. A bad checksum or different data is
+                        // damage, unless the data is recognisably a responder's own art.
+                        let (expected, wrong) = self.verify_data(reply_seq, payload);
+                        let foreign = self.is_foreign_art(art_data);
+                        if !wrong.is_empty() {
+                            print_data_mismatches(&expected, &payload[4..], &wrong);
+                        }
+                        if foreign {
+                            println!("  - Reply carries foreign art instead of ours");
+                        }
+                        if !checksum_note.is_empty() || (!wrong.is_empty() && !foreign) {
+                            self.stats.corrupted += 1;
+                        }
+                        
+                        // Check if we received ASCII art in the reply, after the send time
+                        if !art_data.is_empty() {
+                            self.receive_art(art_data);
+                        }
+                        
+                        // Update statistics
+                        record_reply(&mut self.stats, &mut self.responder_stats, kind, addr, rtt);
+                        if self.sweeping && kind == ReplyKind::First {
+                            self.size_stats.entry(packet_size).or_insert_with(PingStats::new).update(rtt);
+                        }
+                    } else {
+                        println!("  - No send time found for sequence {}", reply_seq);
+                    }
+                } else {
+                    println!("  - Ignoring packet with wrong identifier");
+                }
+            } else {
+                println!("  - Packet payload too short: {} bytes", payload.len());
+            }
+        } else if packet.get_icmp_type() == IcmpTypes::TimestampReply {
+            let now_ms = ms_since_midnight_utc();
+            let payload = packet.payload();
+            match parse_timestamp_reply(payload) {
+                Some(timestamps) => {
+                    let reply_id = ((payload[0] as u16) << 8) | (payload[1] as u16);
+                    let reply_seq = ((payload[2] as u16) << 8) | (payload[3] as u16);
+                    
+                    if reply_id == identifier {
+                        if let Some(sent) = self.sent_times.get(reply_seq) {
+                            let (rtt, clock) = self.round_trip(transport, reply_seq, sent, recv_time, recv_kernel);
+                            let kind = classify_reply(&mut self.replies, reply_seq, sent, addr);
                             
-                            // Print packet type for debugging
-                            println!("Received ICMP packet type: {:?} from {}", packet.get_icmp_type(), addr);
+                            // One-way delays as seen through both clocks, and the
+                            // offset of the remote clock assuming a symmetric path
+                            let outbound = timestamp_diff(timestamps.receive, timestamps.originate);
+                            let inbound = timestamp_diff(now_ms, timestamps.transmit);
+                            let offset = (outbound - inbound) as f64 / 2.0;
                             
-                            if packet.get_icmp_type() == IcmpTypes::EchoReply {
-                                // Extract the payload which should contain our identifier and sequence
-                                let payload = packet.payload();
-                                if payload.len() >= 4 { // Need at least 4 bytes for ID and seq
-                                    let reply_id = ((payload[0] as u16) << 8) | (payload[1] as u16);
-                                    let reply_seq = ((payload[2] as u16) << 8) | (payload[3] as u16);
-                                    
-                                    println!("  - Packet ID: {}, Sequence: {}, Expected ID: {}", 
-                                             reply_id, reply_seq, identifier);
-                                    
-                                    if reply_id == identifier {
-                                        // Calculate round-trip time
-                                        let mut send_times = send_times_clone.lock().unwrap();
-                                        if let Some(send_time) = send_times.remove(&(reply_seq as u64)) {
-                                            let rtt = recv_time.duration_since(send_time).as_secs_f64() * 1000.0;
-                                            
-                                            println!("{} bytes from {}: icmp_seq={} ttl={} time={:.1} ms",
-                                                    packet_size, addr, reply_seq, ttl, rtt);
-                                            
-                                            // Check if we received ASCII art in the reply
-                                            if payload.len() > 4 {
-                                                // Try to extract ASCII art from the payload
-                                                // Start after the ID and sequence bytes
-                                                let art_data = &payload[4..];
-                                                
-                                                // Convert to string, ignoring non-printable characters
-                                                let art_string = art_data.iter()
-                                                    .filter(|&&b| b.is_ascii() && (b.is_ascii_graphic() || b == b' ' || b == b'\n'))
-                                                    .map(|&b| b as char)
-                                                    .collect::<String>();
-                                                
-                                                // If we got something back (some servers just send zeros)
-                                                if !art_string.trim().is_empty() {
-                                                    println!("Received ASCII art in reply:");
-                                                    if rainbow {
-                                                        // Rainbow colors
-                                                        println!("{}", rainbow_text(&art_string));
-                                                    } else {
-                                                        // Pink color
-                                                        println!("{}", pink_text(&art_string));
-                                                    }
-                                                }
-                                            }
-                                            
-                                            // Update statistics
-                                            let mut stats = stats_clone.lock().unwrap();
-                                            stats.update(rtt);
-                                        } else {
-                                            println!("  - No send time found for sequence {}", reply_seq);
-                                        }
-                                    } else {
-                                        println!("  - Ignoring packet with wrong identifier");
-                                    }
-                                } else {
-                                    println!("  - Packet payload too short: {} bytes", payload.len());
-                                }
+                            println!("{} bytes from {}: icmp_seq={}{} time={:.3} ms clock={} offset={:+.1} ms outbound={} ms inbound={} ms{}{}",
+                                    packet.packet().len(), addr, reply_seq, reply_tos, rtt, clock, offset, outbound,
+                                    inbound, kind.suffix(), checksum_note);
+                            print_recorded_options(&recorded);
+                            if !checksum_note.is_empty() {
+                                self.stats.corrupted += 1;
                             }
-                        },
-                        Err(e) => {
-                            if e.kind() != io::ErrorKind::TimedOut && e.kind() != io::ErrorKind::WouldBlock {
-                                eprintln!("Error receiving packet: {}", e);
+                            
+                            // The high bit marks a non-standard timestamp
+                            if (timestamps.receive | timestamps.transmit) & 0x8000_0000 != 0 {
+                                println!("  - Remote clock is not milliseconds since midnight UTC");
                             }
+                            
+                            record_reply(&mut self.stats, &mut self.responder_stats, kind, addr, rtt);
+                        } else {
+                            println!("  - No send time found for sequence {}", reply_seq);
                         }
+                    } else {
+                        println!("  - Ignoring packet with wrong identifier");
                     }
+                },
+                None => {
+                    println!("  - Packet payload too short: {} bytes", payload.len());
                 }
             }
         }
-        
-        println!("Receiver thread shutting down");
-    });
+    }
+}
+
+// Create the capture file the configuration asks for, before any probe needs it
+#[cfg(target_os = "linux")]
+pub fn open_capture(config: &PingConfig) -> Result<Option<Capture>> {
+    config.pcap.as_deref().map(|path| Capture::open(config, path)).transpose()
+}
+
+// Ping implementation using raw sockets
+#[cfg(target_os = "linux")]
+pub fn ping_with_raw_sockets(config: &PingConfig, capture: Option<Capture>) -> Result<()> {
+    // Generate random identifier
+    let identifier = (random::<u16>() % 65535) as u16;
     
-    // Start time for overall statistics
-    let start_time = Instant::now();
+    println!("Using ICMP identifier: {}", identifier);
+    
+    let mut transport = RawSocketTransport::open(config, identifier)?;
+    let shutdown = install_ctrlc_handler()?;
+    run_ping(config, &mut transport, identifier, capture, &shutdown)?;
+    Ok(())
+}
+
+// Ping over an in-memory network instead, which needs no privileges and behaves the same way
+// every time for a given seed
+#[cfg(target_os = "linux")]
+pub fn ping_simulated(config: &PingConfig, spec: &SimulationSpec, capture: Option<Capture>) -> Result<()> {
+    if !config.ip_addr.is_ipv4() {
+        bail!("The simulated network only carries IPv4");
+    }
+    let identifier = (random::<u16>() % 65535) as u16;
     
-    // Main send loop
+    println!("Simulated network: {}", spec);
+    
+    let mut transport = SimulatedTransport::new(spec.clone(), Duration::from_millis(config.interval_ms), probe_source(config),
+                                                config.tos.unwrap_or(0), config.ip_option.map(|option| option.to_bytes()).unwrap_or_default());
+    let shutdown = install_ctrlc_handler()?;
+    run_ping(config, &mut transport, identifier, capture, &shutdown)?;
+    Ok(())
+}
+
+// Send probes and report replies over any transport until the count is reached or `shutdown` stops,
+// returning the statistics it printed.
+// Everything runs on one thread: a poll loop sends probes on schedule and reads replies as they arrive.
+#[cfg(target_os = "linux")]
+pub(crate) fn run_ping(config: &PingConfig, transport: &mut dyn ProbeTransport, identifier: u16,
+                       capture: Option<Capture>, shutdown: &Shutdown) -> Result<PingStats> {
+    let ip_addr = config.ip_addr;
+    let packet_sizes = config.packet_sizes.clone();
+    let max_packet_size = packet_sizes.iter().copied().max().unwrap_or(config.packet_size);
+    let sweeping = packet_sizes.len() > 1;
+    let destination = config.destination.clone();
+    let kernel_timestamps = transport.timestamps();
+    
+    let icmp_timestamp = matches!(config.mode, PingMode::IcmpTimestamp);
+    let mut handler = ReplyHandler {
+        identifier,
+        packet_sizes: packet_sizes.clone(),
+        sweeping,
+        rainbow: config.rainbow,
+        sent_tos: config.tos,
+        kernel_tx: kernel_timestamps.tx,
+        timeout_ns: config.timeout_ms * 1_000_000,
+        sent_times: SeqTimes::new(),
+        tx_times: SeqTimes::new(),
+        replies: HashMap::new(),
+        stats: PingStats::new(),
+        size_stats: BTreeMap::new(),
+        responder_stats: BTreeMap::new(),
+        capture,
+        art: config.art.clone().into_bytes(),
+        chunk_offsets: HashMap::new(),
+        art_chunks: config.chunked_art.then(|| ArtReassembly::new(chunk_size(config.packet_size))),
+    };
+    
+    // Print header
+    if icmp_timestamp {
+        println!("PING {} ({}) ICMP timestamp requests.", destination, ip_addr);
+    } else if sweeping {
+        println!("PING {} ({}) {}-{} bytes of data, cycling {} sizes.",
+                 destination, ip_addr, packet_sizes[0], packet_sizes[packet_sizes.len() - 1], packet_sizes.len());
+    } else {
+        println!("PING {} ({}) {} bytes of data.",
+                 destination, ip_addr, packet_sizes[0]);
+    }
+    println!("Timestamps: {}", describe_timestamps(kernel_timestamps));
+    
+    // Start time for overall statistics, on the transport's clock like every other time here
+    let start_time = transport.now();
+    
+    // Probes go out on a fixed schedule; once the last one is out we wait for its replies
+    let interval = config.interval_ms * 1_000_000;
+    let mut next_send = start_time;
+    let mut linger_until: Option<u64> = None;
+    let mut sequence: u64 = 0;
     let mut total_sent = 0;
-    let mut prev_send_time = Instant::now().checked_sub(Duration::from_millis(config.interval_ms)).unwrap_or_else(Instant::now);
     
-    // Buffer for packet
-    let mut packet_buffer = vec![0u8; packet_size + 8]; // 8 bytes for ICMP header
+    // Buffers for outgoing probes, and whole incoming datagrams so the IPv4 header and its options are available
+    let mut packet_buffer = vec![0u8; (max_packet_size + ICMP_HEADER_LEN).max(ICMP_TIMESTAMP_LEN)];
+    let mut recv_buffer = vec![0u8; 65536];
     
-    while running.load(Ordering::Relaxed) {
-        // Check for interval timing
-        let now = Instant::now();
-        let elapsed = now.duration_since(prev_send_time);
-        
-        if elapsed.as_millis() as u64 >= config.interval_ms {
-            prev_send_time = now;
-            
-            // Increment sequence number
-            let seq = (sequence.fetch_add(1, Ordering::SeqCst) % 65535) as u16;
-            
-            // Store send time
-            {
-                let mut send_times = send_times.lock().unwrap();
-                send_times.insert(seq as u64, Instant::now());
-            }
-            
-            // Create ICMP packet
-            create_icmp_packet(&mut packet_buffer, seq, identifier, packet_size);
-            
-            // Send the packet
-            println!("Sending ICMP packet with seq={}", seq);
-            match tx.send_to(MutableIcmpPacket::new(&mut packet_buffer).unwrap(), ip_addr) {
-                Ok(bytes_sent) => {
-                    println!("Sent {} bytes to {}", bytes_sent, ip_addr);
-                    // Update statistics
-                    let mut stats = stats.lock().unwrap();
-                    stats.packets_sent += 1;
-                    total_sent += 1;
-                },
-                Err(e) => {
-                    eprintln!("Error sending packet: {}", e);
-                }
-            }
-            
-            // Check if we've sent enough packets
-            if let Some(count) = config.count {
-                if total_sent >= count as u64 {
-                    println!("Sent requested number of packets ({}), signaling exit", count);
-                    running.store(false, Ordering::SeqCst);
-                    // Allow some time for the last packet's reply to be received
-                    thread::sleep(Duration::from_millis(500));
+    while shutdown.is_running() {
+        let now = transport.now();
+        match linger_until {
+            Some(deadline) => {
+                // Stop early once every probe is answered, unless more hosts may still answer
+                let all_answered = handler.stats.packets_received >= handler.stats.packets_sent;
+                if now >= deadline || (all_answered && !config.broadcast) {
                     break;
                 }
-            }
+            },
+            None if now >= next_send => {
+                let seq = (sequence % 65535) as u16;
+                sequence += 1;
+                
+                // Create ICMP packet
+                let packet_len = if icmp_timestamp {
+                    create_timestamp_packet(&mut packet_buffer, seq, identifier)
+                } else {
+                    let packet_size = handler.size_for_seq(seq);
+                    let echo_len = packet_size + ICMP_HEADER_LEN;
+                    create_icmp_packet(&mut packet_buffer[..echo_len], seq, identifier, packet_size, &handler.art);
+                    // Carry the next slice of the art instead, cycling through the length the replies
+                    // report, which is the server's art when it answers with its own
+                    if let Some(reassembly) = &handler.art_chunks {
+                        let total = reassembly.total.unwrap_or(handler.art.len());
+                        let offset = chunk_offset(seq as u64, total, reassembly.chunk_size);
+                        write_art_chunk(&mut packet_buffer[ART_CHUNK_START..echo_len], &handler.art, offset);
+                        handler.chunk_offsets.insert(seq, offset);
+                    }
+                    echo_len
+                };
+                
+                // Stamp the send time just before sending, in the payload if there's room for it
+                println!("Sending ICMP packet with seq={}", seq);
+                let sent_ns = transport.now();
+                if icmp_timestamp || !stamp_echo_packet(&mut packet_buffer[..packet_len], sent_ns) {
+                    handler.sent_times.set(seq, sent_ns);
+                }
+                
+                // Send the packet, then pick up its kernel transmit timestamp if it's ready
+                match transport.send(&packet_buffer[..packet_len], ip_addr) {
+                    Ok(bytes_sent) => {
+                        if handler.kernel_tx {
+                            handler.collect_tx_timestamps(transport);
+                        }
+                        // Captured at the kernel transmit time if it's in yet, the userspace send time otherwise
+                        let sent_at = monotonic_to_wall(handler.tx_times.get(seq).filter(|&tx| tx >= sent_ns).unwrap_or(sent_ns));
+                        handler.capture(|capture| capture.probe(sent_at, &packet_buffer[..packet_len]));
+                        println!("Sent {} bytes to {}", bytes_sent, ip_addr);
+                        // Update statistics
+                        handler.stats.packets_sent += 1;
+                        total_sent += 1;
+                        if sweeping && !icmp_timestamp {
+                            let packet_size = handler.size_for_seq(seq);
+                            handler.size_stats.entry(packet_size).or_insert_with(PingStats::new).packets_sent += 1;
+                        }
+                    },
+                    Err(e) => {
+                        eprintln!("Error sending packet: {}", e);
+                    }
+                }
+                
+                // Keep to the schedule, unless we've fallen a whole interval behind
+                next_send += interval;
+                if next_send < now {
+                    next_send = now + interval;
+                }
+                
+                // Check if we've sent enough packets, then give the last replies until the timeout
+                if let Some(count) = config.count {
+                    if total_sent >= count as u64 {
+                        println!("Sent requested number of packets ({}), waiting for replies", count);
+                        linger_until = Some(transport.now() + config.timeout_ms * 1_000_000);
+                    }
+                }
+                continue;
+            },
+            None => {}
         }
         
-        // Break if running flag is false (e.g., due to CTRL+C)
-        if !running.load(Ordering::Relaxed) {
-            println!("Main thread detected exit signal");
-            break;
+        // Sleep until the next send or a reply arrives, whichever is first
+        let wake_at = linger_until.unwrap_or(next_send);
+        let ready = transport.wait(shutdown.fd(), Duration::from_nanos(wake_at.saturating_sub(transport.now())))
+            .context("Failed to wait for replies")?;
+        if ready {
+            handler.drain(transport, &mut recv_buffer);
         }
-        
-        // Small sleep to avoid burning CPU
-        thread::sleep(Duration::from_millis(10));
     }
     
-    // Signal the receiver thread to exit
-    running.store(false, Ordering::SeqCst);
-    println!("Waiting for receiver thread to finish...");
-    
-    // Wait for receiver thread to finish
-    match receiver_thread.join() {
-        Ok(_) => println!("Receiver thread joined successfully"),
-        Err(e) => eprintln!("Error joining receiver thread: {:?}", e),
+    if !shutdown.is_running() {
+        println!("Exit signal received");
     }
     
     // Print statistics
-    let stats = stats.lock().unwrap();
-    let elapsed = start_time.elapsed().as_secs_f64();
+    print_statistics(&destination, &handler.stats, Duration::from_nanos(transport.now() - start_time));
+    if config.broadcast {
+        print_responders(&handler.responder_stats, handler.stats.packets_sent);
+    }
+    if sweeping && !icmp_timestamp {
+        print_size_sweep(&handler.size_stats);
+    }
     
-    println!("\n--- {} ping statistics ---", destination);
-    println!("{} packets transmitted, {} received, {:.1}% packet loss, time {:.0}ms",
-             stats.packets_sent, stats.packets_received, stats.packet_loss(), elapsed * 1000.0);
+    // Say which chunks of the art never made it back
+    if let Some(reassembly) = handler.art_chunks.as_ref().filter(|reassembly| !reassembly.rendered) {
+        let missing: Vec<usize> = reassembly.missing().iter().map(|index| index + 1).collect();
+        match reassembly.total {
+            Some(_) => println!("Art incomplete: {} of {} chunks received, missing {}",
+                                reassembly.chunk_count() - missing.len(), reassembly.chunk_count(), describe_ranges(&missing)),
+            None => println!("Art incomplete: no chunks received"),
+        }
+    }
     
-    if stats.packets_received > 0 {
-        println!("rtt min/avg/max = {:.3}/{:.3}/{:.3} ms",
-                 stats.rtt_min, stats.avg_rtt(), stats.rtt_max);
+    // Finish the capture file
+    if let (Some(capture), Some(path)) = (handler.capture.take(), &config.pcap) {
+        let packets = capture.writer.finish().context("Failed to write the pcap file")?;
+        println!("Wrote {} packets to {}", packets, path.display());
     }
     
-    Ok(())
-} 
\ No newline at end of file
+    Ok(handler.stats)
+}
@@ -0,0 +1,176 @@
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, anyhow, bail};
+use pnet::packet::icmp::{IcmpPacket, IcmpTypes, MutableIcmpPacket};
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::Packet;
+use pnet_transport::{icmp_packet_iter, transport_channel, TransportChannelType};
+use pnet_transport::TransportProtocol::Ipv4;
+use rand::random;
+
+use crate::config::PingConfig;
+use crate::packet::{create_icmp_packet, ICMP_HEADER_LEN};
+use crate::socket::{apply_source, attach_icmp_filter, route_mtu, set_mtu_discover, PmtuDiscovery};
+
+// Size of the IPv4 header we send with, no options
+const IPV4_HEADER_LEN: usize = 20;
+
+// Largest ICMP payload that fits in an IPv4 datagram
+const MAX_ICMP_PAYLOAD: usize = 65535 - IPV4_HEADER_LEN - ICMP_HEADER_LEN;
+
+// How many times a size is tried before a lack of replies counts as too big
+const ATTEMPTS_PER_SIZE: usize = 2;
+
+// Result of probing a single payload size
+enum ProbeResult {
+    // An echo reply came back
+    Fits(f64),
+    // A router or the local stack reported the packet too big, with the MTU it suggested
+    TooBig(Option<usize>),
+    // Nothing came back before the timeout
+    NoReply,
+}
+
+// Extract the identifier and sequence of our echo request quoted in an ICMP error
+fn quoted_echo(error_payload: &[u8]) -> Option<(u16, u16)> {
+    // The quoted datagram starts after the 4 bytes of unused/next-hop MTU
+    let quoted = error_payload.get(4..)?;
+    let ip_header = Ipv4Packet::new(quoted)?;
+    let quoted_icmp = quoted.get(ip_header.get_header_length() as usize * 4..)?;
+    let icmp = IcmpPacket::new(quoted_icmp)?;
+    if icmp.get_icmp_type() != IcmpTypes::EchoRequest || quoted_icmp.len() < 8 {
+        return None;
+    }
+    let id = u16::from_be_bytes([quoted_icmp[4], quoted_icmp[5]]);
+    let seq = u16::from_be_bytes([quoted_icmp[6], quoted_icmp[7]]);
+    Some((id, seq))
+}
+
+// Discover the largest ICMP payload that reaches the destination without fragmentation
+pub fn discover_pmtu(config: &PingConfig) -> Result<()> {
+    let ip_addr = match config.ip_addr {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(_) => bail!("Path MTU discovery is only supported for IPv4 destinations"),
+    };
+    let timeout = Duration::from_millis(config.timeout_ms);
+    let identifier = random::<u16>();
+
+    // Upper bound from the MTU of the route the kernel would use
+    let mtu = route_mtu(config.ip_addr)?;
+    let mut hi = mtu.saturating_sub(IPV4_HEADER_LEN + ICMP_HEADER_LEN).min(MAX_ICMP_PAYLOAD);
+    let mut lo = 0usize;
+
+    let protocol = Ipv4(pnet::packet::ip::IpNextHeaderProtocol(1));
+    let (mut tx, mut rx) = transport_channel(65536, TransportChannelType::Layer4(protocol))
+        .map_err(|e| anyhow!("Failed to create ICMP socket: {}", e))?;
+    let mut icmp_iter = icmp_packet_iter(&mut rx);
+
+    // Always set Don't Fragment so oversized probes are reported instead of fragmented
+    set_mtu_discover(tx.socket.fd, PmtuDiscovery::Do)?;
+    apply_source(tx.socket.fd, config.source.as_ref(), config.mark)?;
+
+    // Only replies to our probes and Destination Unreachable errors quoting them get through
+    if let Err(e) = attach_icmp_filter(tx.socket.fd, identifier, &[IcmpTypes::EchoReply.0],
+                                       &[IcmpTypes::DestinationUnreachable.0]) {
+        eprintln!("Failed to attach BPF filter, filtering replies in userspace: {}", e);
+    }
+
+    println!("PMTU {} ({}): searching payload sizes {}..{} (route MTU {})",
+             config.destination, ip_addr, lo, hi, mtu);
+
+    let mut packet_buffer = vec![0u8; hi + ICMP_HEADER_LEN];
+    let mut sequence: u16 = 0;
+    let mut largest_fit: Option<usize> = None;
+
+    // Probe a single size, retrying when nothing comes back
+    let mut probe = |size: usize| -> Result<ProbeResult> {
+        for _ in 0..ATTEMPTS_PER_SIZE {
+            let seq = sequence;
+            sequence = sequence.wrapping_add(1);
+
+            let packet_len = size + ICMP_HEADER_LEN;
+            create_icmp_packet(&mut packet_buffer[..packet_len], seq, identifier, size, config.art.as_bytes());
+
+            let send_time = Instant::now();
+            let packet = MutableIcmpPacket::new(&mut packet_buffer[..packet_len]).unwrap();
+            if let Err(e) = tx.send_to(packet, IpAddr::V4(ip_addr)) {
+                // The local stack refuses packets larger than the path MTU it already knows
+                if e.raw_os_error() == Some(libc::EMSGSIZE) {
+                    return Ok(ProbeResult::TooBig(route_mtu(config.ip_addr).ok()));
+                }
+                return Err(e.into());
+            }
+
+            // Wait for the reply or an error quoting this probe
+            let deadline = send_time + timeout;
+            loop {
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                let (packet, addr) = match icmp_iter.next_with_timeout(deadline - now)? {
+                    Some(received) => received,
+                    None => break,
+                };
+                let payload = packet.payload();
+
+                match packet.get_icmp_type() {
+                    IcmpTypes::EchoReply if payload.len() >= 4 => {
+                        let reply_id = u16::from_be_bytes([payload[0], payload[1]]);
+                        let reply_seq = u16::from_be_bytes([payload[2], payload[3]]);
+                        if reply_id == identifier && reply_seq == seq {
+                            return Ok(ProbeResult::Fits(send_time.elapsed().as_secs_f64() * 1000.0));
+                        }
+                    },
+                    // Fragmentation Needed carries the next-hop MTU in bytes 2-3 of the rest of header
+                    IcmpTypes::DestinationUnreachable if packet.get_icmp_code().0 == 4
+                        && quoted_echo(payload) == Some((identifier, seq)) => {
+                        let next_hop_mtu = u16::from_be_bytes([payload[2], payload[3]]) as usize;
+                        println!("  - Fragmentation needed from {} (next-hop MTU {})", addr, next_hop_mtu);
+                        return Ok(ProbeResult::TooBig(Some(next_hop_mtu).filter(|&mtu| mtu > 0)));
+                    },
+                    _ => {}
+                }
+            }
+        }
+        Ok(ProbeResult::NoReply)
+    };
+
+    // Binary search for the largest payload that gets a reply
+    while lo <= hi {
+        let mid = lo + (hi - lo) / 2;
+        match probe(mid)? {
+            ProbeResult::Fits(rtt) => {
+                println!("{} bytes: ok time={:.1} ms", mid, rtt);
+                largest_fit = Some(mid);
+                lo = mid + 1;
+            },
+            ProbeResult::TooBig(suggested_mtu) => {
+                println!("{} bytes: too big", mid);
+                hi = mid.saturating_sub(1);
+                // Jump straight to what the router told us fits, if that's smaller
+                if let Some(mtu) = suggested_mtu {
+                    hi = hi.min(mtu.saturating_sub(IPV4_HEADER_LEN + ICMP_HEADER_LEN));
+                }
+            },
+            ProbeResult::NoReply => {
+                println!("{} bytes: no reply", mid);
+                hi = mid.saturating_sub(1);
+            },
+        }
+        if mid == 0 {
+            break;
+        }
+    }
+
+    println!("\n--- {} path MTU ---", config.destination);
+    match largest_fit {
+        Some(size) => {
+            println!("path MTU {} bytes (largest ICMP payload {} bytes)",
+                     size + IPV4_HEADER_LEN + ICMP_HEADER_LEN, size);
+            Ok(())
+        },
+        None => Err(anyhow!("No replies received from {}", config.destination)),
+    }
+}
@@ -0,0 +1,194 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use pnet::packet::icmp::{IcmpCode, IcmpPacket, IcmpTypes};
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::Packet;
+use rand::random;
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+
+use crate::art::{parse_art_chunk, write_art_chunk};
+use crate::config::PingConfig;
+use crate::packet::{build_icmp_message, echo_art_data, ECHO_TIMESTAMP_LEN, ICMP_HEADER_LEN};
+use crate::ping::install_ctrlc_handler;
+use crate::socket::{apply_source, recv_msg, set_nonblocking, wait_readable};
+
+// Where the kernel's own echo replies are switched off
+const ICMP_ECHO_IGNORE_ALL: &str = "/proc/sys/net/ipv4/icmp_echo_ignore_all";
+
+// Settings for `bingping serve`
+#[derive(Clone, Debug)]
+pub struct ServeOptions {
+    // Listen on this UDP port instead of answering ICMP echo requests
+    pub udp_port: Option<u16>,
+    pub delay: Duration,
+    // Probability of ignoring a request, from 0 to 1
+    pub loss: f64,
+}
+
+// Reply data the same length as the request's, keeping the leading bytes where clients carry
+// their send time and filling the rest with art, or the slice of it a chunked request asks for
+fn art_payload(request: &[u8], art: &[u8]) -> Vec<u8> {
+    let mut data = vec![b'#'; request.len()];
+    let keep = ECHO_TIMESTAMP_LEN.min(request.len());
+    data[..keep].copy_from_slice(&request[..keep]);
+    if let Some(chunk) = parse_art_chunk(&request[keep..]) {
+        write_art_chunk(&mut data[keep..], art, chunk.offset);
+        return data;
+    }
+    let art_data = echo_art_data(art, data.len() - keep);
+    data[keep..].copy_from_slice(&art_data);
+    data
+}
+
+// A reply waiting out the artificial delay
+struct Reply {
+    due: Instant,
+    to: SocketAddr,
+    packet: Vec<u8>,
+}
+
+// The socket requests arrive on
+enum Listener {
+    Icmp(Socket),
+    Udp(UdpSocket),
+}
+
+impl Listener {
+    fn fd(&self) -> RawFd {
+        match self {
+            Listener::Icmp(socket) => socket.as_raw_fd(),
+            Listener::Udp(socket) => socket.as_raw_fd(),
+        }
+    }
+
+    // Read one datagram, returning who sent it, a description for the log and the reply to
+    // send, or None for anything that isn't a request we answer
+    fn recv_request(&self, buffer: &mut [u8], art: &[u8]) -> io::Result<Option<(SocketAddr, String, Vec<u8>)>> {
+        match self {
+            Listener::Icmp(socket) => {
+                let received = recv_msg(socket.as_raw_fd(), buffer)?;
+                let datagram = &buffer[..received.len];
+                let Some(ip) = Ipv4Packet::new(datagram) else {
+                    return Ok(None);
+                };
+                let Some(request) = datagram.get(ip.get_header_length() as usize * 4..).and_then(IcmpPacket::new) else {
+                    return Ok(None);
+                };
+                let payload = request.payload();
+                if request.get_icmp_type() != IcmpTypes::EchoRequest || payload.len() < 4 {
+                    return Ok(None);
+                }
+                let rest_of_header = [payload[0], payload[1], payload[2], payload[3]];
+                let data = art_payload(&payload[4..], art);
+                let mut reply = vec![0u8; ICMP_HEADER_LEN + data.len()];
+                build_icmp_message(&mut reply, IcmpTypes::EchoReply, IcmpCode(0), rest_of_header, &data);
+                let description = format!("icmp_seq={} {} bytes", u16::from_be_bytes([payload[2], payload[3]]), data.len());
+                Ok(Some((SocketAddr::new(received.from, 0), description, reply)))
+            },
+            Listener::Udp(socket) => {
+                let (len, from) = socket.recv_from(buffer)?;
+                Ok(Some((from, format!("udp {} bytes", len), art_payload(&buffer[..len], art))))
+            },
+        }
+    }
+
+    fn send(&self, reply: &Reply) -> io::Result<usize> {
+        match self {
+            Listener::Icmp(socket) => socket.send_to(&reply.packet, &SockAddr::from(reply.to)),
+            Listener::Udp(socket) => socket.send_to(&reply.packet, reply.to),
+        }
+    }
+}
+
+// Open the ICMP or UDP socket requests arrive on
+fn listen(config: &PingConfig, options: &ServeOptions) -> Result<Listener> {
+    let (socket, listener_type) = match options.udp_port {
+        Some(_) => (Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)), "UDP"),
+        None => (Socket::new(Domain::IPV4, Type::from(libc::SOCK_RAW), Some(Protocol::ICMPV4)), "ICMP"),
+    };
+    let socket = socket.with_context(|| format!("Failed to create {} socket", listener_type))?;
+    apply_source(socket.as_raw_fd(), config.source.as_ref(), config.mark)
+        .context("Failed to bind to the source interface or set the firewall mark")?;
+    set_nonblocking(socket.as_raw_fd()).context("Failed to make the socket non-blocking")?;
+
+    match options.udp_port {
+        Some(port) => {
+            socket.set_reuse_address(true)?;
+            let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port);
+            socket.bind(&addr.into()).with_context(|| format!("Failed to bind UDP port {}", port))?;
+            println!("Answering UDP datagrams on port {} with bingus art", port);
+            Ok(Listener::Udp(socket.into()))
+        },
+        None => {
+            // Unless the kernel is told not to, it answers every request too and clients see duplicates
+            if fs::read_to_string(ICMP_ECHO_IGNORE_ALL).is_ok_and(|value| value.trim() == "0") {
+                eprintln!("Warning: net.ipv4.icmp_echo_ignore_all is 0, so the kernel answers echo requests as well; \
+                           set it with `sysctl -w net.ipv4.icmp_echo_ignore_all=1`");
+            }
+            println!("Answering ICMP echo requests with bingus art");
+            Ok(Listener::Icmp(socket))
+        },
+    }
+}
+
+// Answer echo requests (or UDP datagrams) with the art in place of the data they carried,
+// optionally delaying or dropping some, until CTRL+C
+pub fn serve(config: &PingConfig, options: &ServeOptions) -> Result<()> {
+    let art = config.art.as_bytes();
+    let listener = listen(config, options)?;
+    if !options.delay.is_zero() || options.loss > 0.0 {
+        println!("Delaying replies by {} ms, dropping {:.1}% of requests",
+                 options.delay.as_millis(), options.loss * 100.0);
+    }
+
+    let shutdown = install_ctrlc_handler()?;
+    let mut buffer = vec![0u8; 65536];
+    // Every reply waits the same delay, so they're due in the order they were queued
+    let mut pending: VecDeque<Reply> = VecDeque::new();
+    let (mut requests, mut answered, mut dropped) = (0u64, 0u64, 0u64);
+
+    while shutdown.is_running() {
+        let timeout = pending.front().map(|reply| reply.due.saturating_duration_since(Instant::now()));
+        let ready = wait_readable(&[listener.fd(), shutdown.fd()], timeout).context("Failed to wait for requests")?;
+
+        // Send whatever has waited long enough
+        while pending.front().is_some_and(|reply| reply.due <= Instant::now()) {
+            let reply = pending.pop_front().unwrap();
+            match listener.send(&reply) {
+                Ok(_) => answered += 1,
+                Err(e) => eprintln!("Error sending reply to {}: {}", reply.to.ip(), e),
+            }
+        }
+
+        if !ready[0] {
+            continue;
+        }
+        loop {
+            let (to, description, packet) = match listener.recv_request(&mut buffer, art) {
+                Ok(Some(request)) => request,
+                Ok(None) => continue,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e).context("Failed to receive requests"),
+            };
+            requests += 1;
+            if options.loss > 0.0 && random::<f64>() < options.loss {
+                dropped += 1;
+                println!("Request from {}: {} (dropped)", to.ip(), description);
+                continue;
+            }
+            println!("Request from {}: {}", to.ip(), description);
+            pending.push_back(Reply { due: Instant::now() + options.delay, to, packet });
+        }
+    }
+
+    println!("\n--- serve statistics ---");
+    println!("{} requests, {} answered, {} dropped", requests, answered, dropped);
+
+    Ok(())
+}
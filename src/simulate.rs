@@ -0,0 +1,499 @@
+use std::fmt;
+#[cfg(target_os = "linux")]
+use std::io;
+#[cfg(target_os = "linux")]
+use std::net::{IpAddr, Ipv4Addr};
+#[cfg(target_os = "linux")]
+use std::os::unix::io::RawFd;
+#[cfg(target_os = "linux")]
+use std::time::Duration;
+
+#[cfg(target_os = "linux")]
+use pnet::packet::icmp::{IcmpCode, IcmpTypes};
+#[cfg(target_os = "linux")]
+use rand::rngs::StdRng;
+#[cfg(target_os = "linux")]
+use rand::{Rng, SeedableRng};
+
+#[cfg(target_os = "linux")]
+use crate::packet::{build_icmp_message, build_ipv4_datagram, ms_since_midnight_utc, record_hop, ICMP_HEADER_LEN,
+                    ICMP_TIMESTAMP_LEN, IPV4_HEADER_LEN};
+#[cfg(target_os = "linux")]
+use crate::socket::{monotonic_ns, wait_readable, KernelTimestamps, Received};
+#[cfg(target_os = "linux")]
+use crate::transport::ProbeTransport;
+
+// Milliseconds in a day, where ICMP timestamps wrap
+#[cfg(target_os = "linux")]
+const MS_PER_DAY: u32 = 86_400_000;
+
+// Shape of the round-trip delay around the configured latency
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LatencyDistribution {
+    // Normally distributed, with the jitter as standard deviation
+    Normal,
+    // Anywhere within the jitter either side of the latency
+    Uniform,
+    // Never below the latency, with an exponential tail averaging the jitter
+    Exponential,
+}
+
+// How the simulated network treats each probe
+#[derive(Clone, Debug)]
+pub struct SimulationSpec {
+    pub latency_ms: f64,
+    pub jitter_ms: f64,
+    pub distribution: LatencyDistribution,
+    // Probabilities from 0 to 1
+    pub loss: f64,
+    pub duplicate: f64,
+    pub reorder: f64,
+    pub corrupt: f64,
+    // Seed for every random choice, so a run can be repeated exactly
+    pub seed: u64,
+}
+
+impl Default for SimulationSpec {
+    fn default() -> Self {
+        SimulationSpec {
+            latency_ms: 10.0,
+            jitter_ms: 0.0,
+            distribution: LatencyDistribution::Normal,
+            loss: 0.0,
+            duplicate: 0.0,
+            reorder: 0.0,
+            corrupt: 0.0,
+            seed: 1,
+        }
+    }
+}
+
+impl fmt::Display for SimulationSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let distribution = match self.distribution {
+            LatencyDistribution::Normal => "normal",
+            LatencyDistribution::Uniform => "uniform",
+            LatencyDistribution::Exponential => "exponential",
+        };
+        write!(f, "latency {:.1} ms ({}, jitter {:.1} ms), loss {:.1}%, duplicate {:.1}%, reorder {:.1}%, corrupt {:.1}%, seed {}",
+               self.latency_ms, distribution, self.jitter_ms, self.loss * 100.0, self.duplicate * 100.0,
+               self.reorder * 100.0, self.corrupt * 100.0, self.seed)
+    }
+}
+
+// Parse a time in milliseconds, with an optional `ms` suffix
+fn parse_ms(value: &str) -> Result<f64, String> {
+    match value.strip_suffix("ms").unwrap_or(value).parse::<f64>() {
+        Ok(ms) if ms.is_finite() && ms >= 0.0 => Ok(ms),
+        _ => Err(format!("Invalid time '{}', expected milliseconds like 20 or 20ms", value)),
+    }
+}
+
+// Parse a probability as a fraction or a percentage
+pub fn parse_probability(value: &str) -> Result<f64, String> {
+    let parsed = match value.strip_suffix('%') {
+        Some(percent) => percent.parse::<f64>().map(|p| p / 100.0),
+        None => value.parse::<f64>(),
+    };
+    match parsed {
+        Ok(p) if (0.0..=1.0).contains(&p) => Ok(p),
+        _ => Err(format!("Invalid probability '{}', expected 0-1 or 0%-100%", value)),
+    }
+}
+
+// Parse comma-separated `key=value` settings, such as `latency=20,jitter=5,loss=10%,seed=7`.
+// Anything left out keeps its default.
+pub fn parse_simulation(spec: &str) -> Result<SimulationSpec, String> {
+    let mut simulation = SimulationSpec::default();
+    for setting in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (key, value) = setting.split_once('=')
+            .ok_or_else(|| format!("Expected key=value, got '{}'", setting))?;
+        let value = value.trim();
+        match key.trim() {
+            "latency" => simulation.latency_ms = parse_ms(value)?,
+            "jitter" => simulation.jitter_ms = parse_ms(value)?,
+            "dist" | "distribution" => simulation.distribution = match value {
+                "normal" => LatencyDistribution::Normal,
+                "uniform" => LatencyDistribution::Uniform,
+                "exponential" | "exp" => LatencyDistribution::Exponential,
+                _ => return Err(format!("Unknown distribution '{}', expected normal, uniform or exponential", value)),
+            },
+            "loss" => simulation.loss = parse_probability(value)?,
+            "dup" | "duplicate" => simulation.duplicate = parse_probability(value)?,
+            "reorder" => simulation.reorder = parse_probability(value)?,
+            "corrupt" => simulation.corrupt = parse_probability(value)?,
+            "seed" => simulation.seed = value.parse::<u64>()
+                .map_err(|_| format!("Invalid seed '{}'", value))?,
+            other => return Err(format!(
+                "Unknown setting '{}', expected latency, jitter, dist, loss, dup, reorder, corrupt or seed", other)),
+        }
+    }
+    Ok(simulation)
+}
+
+// A reply on its way back, due at a time on the simulated clock
+#[cfg(target_os = "linux")]
+struct Delivery {
+    due: u64,
+    from: IpAddr,
+    datagram: Vec<u8>,
+}
+
+// An in-memory network that answers echo and timestamp requests itself, losing, duplicating,
+// delaying, reordering and corrupting replies as the spec asks. Needs no privileges.
+// Time on the network only moves forward while waiting, so round trips are exactly the delays drawn
+// from the seed, however busy the machine is.
+#[cfg(target_os = "linux")]
+pub struct SimulatedTransport {
+    spec: SimulationSpec,
+    rng: StdRng,
+    // Simulated monotonic time in nanoseconds, starting from the real clock
+    clock: u64,
+    // Whether waits also take real time, so probes go out at the interval a user would expect
+    paced: bool,
+    // Extra delay for a reordered reply, enough to land behind the reply to the next probe
+    reorder_delay_ns: u64,
+    // Where replies are addressed to
+    local: Ipv4Addr,
+    // TOS and IP options our probes carry, which the far end sends back with its replies
+    tos: u8,
+    options: Vec<u8>,
+    in_flight: Vec<Delivery>,
+}
+
+#[cfg(target_os = "linux")]
+impl SimulatedTransport {
+    pub fn new(spec: SimulationSpec, interval: Duration, local: Ipv4Addr, tos: u8, options: Vec<u8>) -> Self {
+        SimulatedTransport {
+            rng: StdRng::seed_from_u64(spec.seed),
+            clock: monotonic_ns(),
+            paced: true,
+            reorder_delay_ns: interval.as_nanos() as u64 * 3 / 2,
+            local,
+            tos,
+            options,
+            spec,
+            in_flight: Vec::new(),
+        }
+    }
+
+    // Whether something with probability `p` happens this time
+    fn chance(&mut self, p: f64) -> bool {
+        p > 0.0 && self.rng.gen_bool(p)
+    }
+
+    // Round-trip delay of one reply in nanoseconds, drawn from the latency distribution
+    fn sample_delay(&mut self) -> u64 {
+        let (latency, jitter) = (self.spec.latency_ms, self.spec.jitter_ms);
+        let ms = match self.spec.distribution {
+            // Box-Muller transform
+            LatencyDistribution::Normal => {
+                let (u1, u2) = (self.rng.gen_range(f64::EPSILON..1.0), self.rng.gen::<f64>());
+                latency + jitter * (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+            },
+            LatencyDistribution::Uniform => latency + jitter * self.rng.gen_range(-1.0..=1.0),
+            LatencyDistribution::Exponential => latency - jitter * self.rng.gen_range(f64::EPSILON..1.0).ln(),
+        };
+        (ms.max(0.0) * 1_000_000.0) as u64
+    }
+
+    // Milliseconds since midnight UTC at the far end halfway through a round trip, by a clock in step with ours
+    fn remote_ms(delay_ns: u64) -> u32 {
+        ((ms_since_midnight_utc() as u64 + delay_ns / 2_000_000) % MS_PER_DAY as u64) as u32
+    }
+
+    // The ICMP reply a host would send to `request`, or None for messages it wouldn't answer
+    fn reply_to(request: &[u8], delay_ns: u64) -> Option<Vec<u8>> {
+        let rest_of_header: [u8; 4] = request.get(4..ICMP_HEADER_LEN)?.try_into().ok()?;
+        let mut reply = vec![0u8; request.len()];
+        let len = match request[0] {
+            8 => build_icmp_message(&mut reply, IcmpTypes::EchoReply, IcmpCode(0), rest_of_header,
+                                    &request[ICMP_HEADER_LEN..]),
+            13 if request.len() >= ICMP_TIMESTAMP_LEN => {
+                // Received and sent back halfway through the round trip
+                let mut times = [0u8; 12];
+                times[..4].copy_from_slice(&request[ICMP_HEADER_LEN..ICMP_HEADER_LEN + 4]);
+                let remote = Self::remote_ms(delay_ns);
+                times[4..8].copy_from_slice(&remote.to_be_bytes());
+                times[8..12].copy_from_slice(&remote.to_be_bytes());
+                build_icmp_message(&mut reply, IcmpTypes::TimestampReply, IcmpCode(0), rest_of_header, &times)
+            },
+            _ => return None,
+        };
+        reply.truncate(len);
+        Some(reply)
+    }
+
+    // Flip one random bit of the ICMP message, leaving the checksum as it was
+    fn corrupt(&mut self, datagram: &mut [u8]) {
+        let header_len = IPV4_HEADER_LEN + self.options.len();
+        let bit = self.rng.gen_range(header_len * 8..datagram.len() * 8);
+        datagram[bit / 8] ^= 1 << (bit % 8);
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl ProbeTransport for SimulatedTransport {
+    fn send(&mut self, packet: &[u8], dest: IpAddr) -> io::Result<usize> {
+        let IpAddr::V4(dest_v4) = dest else {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "the simulated network only carries IPv4"));
+        };
+        if self.chance(self.spec.loss) {
+            return Ok(packet.len());
+        }
+        let mut delay = self.sample_delay();
+        let Some(reply) = Self::reply_to(packet, delay) else {
+            return Ok(packet.len());
+        };
+        // The far end echoes the probe's TOS and options, noting itself in any that record the route
+        let mut options = self.options.clone();
+        record_hop(&mut options, dest_v4, Self::remote_ms(delay));
+        let mut datagram = build_ipv4_datagram(dest_v4, self.local, 64, self.tos, &options, &reply);
+        if self.chance(self.spec.corrupt) {
+            self.corrupt(&mut datagram);
+        }
+        if self.chance(self.spec.reorder) {
+            delay += self.reorder_delay_ns;
+        }
+
+        let now = self.clock;
+        if self.chance(self.spec.duplicate) {
+            let due = now + delay + self.sample_delay();
+            self.in_flight.push(Delivery { due, from: dest, datagram: datagram.clone() });
+        }
+        self.in_flight.push(Delivery { due: now + delay, from: dest, datagram });
+        Ok(packet.len())
+    }
+
+    fn recv(&mut self, buffer: &mut [u8]) -> io::Result<Received> {
+        let next = self.in_flight.iter()
+            .enumerate()
+            .filter(|(_, delivery)| delivery.due <= self.clock)
+            .min_by_key(|(_, delivery)| delivery.due)
+            .map(|(index, _)| index);
+        let Some(index) = next else {
+            return Err(io::ErrorKind::WouldBlock.into());
+        };
+        let delivery = self.in_flight.remove(index);
+        let len = delivery.datagram.len().min(buffer.len());
+        buffer[..len].copy_from_slice(&delivery.datagram[..len]);
+        Ok(Received { len, from: delivery.from, kernel_time: None })
+    }
+
+    // Move the clock on to the next reply or the timeout, whichever is first
+    fn wait(&mut self, wake: RawFd, timeout: Duration) -> io::Result<bool> {
+        let mut until = self.clock.saturating_add(timeout.as_nanos() as u64);
+        if let Some(due) = self.in_flight.iter().map(|delivery| delivery.due).min() {
+            until = until.min(due.max(self.clock));
+        }
+        if self.paced {
+            let start = monotonic_ns();
+            let woken = wait_readable(&[wake], Some(Duration::from_nanos(until - self.clock)))?[0];
+            if woken {
+                until = until.min(self.clock + (monotonic_ns() - start));
+            }
+        }
+        self.clock = until;
+        Ok(self.in_flight.iter().any(|delivery| delivery.due <= self.clock))
+    }
+
+    fn tx_timestamps(&mut self) -> Vec<(u16, u64)> {
+        Vec::new()
+    }
+
+    // Replies are read at the moment they're due, so there's nothing more precise to offer
+    fn timestamps(&self) -> KernelTimestamps {
+        KernelTimestamps { rx: false, tx: false }
+    }
+
+    fn now(&self) -> u64 {
+        self.clock
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use pnet::packet::ipv4::Ipv4Packet;
+
+    use crate::config::{PingConfig, PingMode};
+    use crate::packet::{parse_ip_options, IpOption};
+    use crate::ping::{run_ping, Shutdown};
+    use crate::stats::PingStats;
+
+    const INTERVAL_MS: u64 = 100;
+
+    fn config(count: u16) -> PingConfig {
+        PingConfig {
+            mode: PingMode::Icmp,
+            destination: "10.0.0.1".to_string(),
+            ip_addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            count: Some(count),
+            packet_size: 64,
+            packet_sizes: vec![64],
+            interval_ms: INTERVAL_MS,
+            timeout_ms: 1000,
+            ttl: 64,
+            quiet: false,
+            rainbow: false,
+            pmtu_discovery: None,
+            ip_option: None,
+            tos: None,
+            source: None,
+            mark: None,
+            bypass_route: false,
+            broadcast: false,
+            simulate: None,
+            pcap: None,
+            chunked_art: false,
+            art: "art".to_string(),
+        }
+    }
+
+    // The simulated network, keeping every datagram the ping loop reads in the order it read them
+    struct Recording {
+        network: SimulatedTransport,
+        received: Vec<Vec<u8>>,
+    }
+
+    impl ProbeTransport for Recording {
+        fn send(&mut self, packet: &[u8], dest: IpAddr) -> io::Result<usize> {
+            self.network.send(packet, dest)
+        }
+
+        fn recv(&mut self, buffer: &mut [u8]) -> io::Result<Received> {
+            let received = self.network.recv(buffer)?;
+            self.received.push(buffer[..received.len].to_vec());
+            Ok(received)
+        }
+
+        fn wait(&mut self, wake: RawFd, timeout: Duration) -> io::Result<bool> {
+            self.network.wait(wake, timeout)
+        }
+
+        fn tx_timestamps(&mut self) -> Vec<(u16, u64)> {
+            self.network.tx_timestamps()
+        }
+
+        fn timestamps(&self) -> KernelTimestamps {
+            self.network.timestamps()
+        }
+
+        fn now(&self) -> u64 {
+            self.network.now()
+        }
+    }
+
+    // Ping over the simulated network without waiting in real time, returning the statistics
+    // and the replies as they were read
+    fn simulate_with(config: &PingConfig, spec: &str) -> (PingStats, Vec<Vec<u8>>) {
+        let options = config.ip_option.map(|option| option.to_bytes()).unwrap_or_default();
+        let mut network = SimulatedTransport::new(parse_simulation(spec).unwrap(), Duration::from_millis(INTERVAL_MS),
+                                                  Ipv4Addr::new(10, 0, 0, 2), config.tos.unwrap_or(0), options);
+        network.paced = false;
+        let mut transport = Recording { network, received: Vec::new() };
+        let stats = run_ping(config, &mut transport, 4242, None, &Shutdown::new().unwrap()).unwrap();
+        (stats, transport.received)
+    }
+
+    fn simulate(spec: &str, count: u16) -> PingStats {
+        simulate_with(&config(count), spec).0
+    }
+
+    // Sequence number of the echo reply in a datagram
+    fn sequence(datagram: &[u8]) -> u16 {
+        let icmp = &datagram[(datagram[0] & 0x0F) as usize * 4..];
+        u16::from_be_bytes([icmp[6], icmp[7]])
+    }
+
+    #[test]
+    fn parses_specs() {
+        let spec = parse_simulation("latency=20ms, jitter=5,dist=exp,loss=10%,dup=0.5,reorder=1%,corrupt=0,seed=7").unwrap();
+        assert_eq!((spec.latency_ms, spec.jitter_ms, spec.distribution), (20.0, 5.0, LatencyDistribution::Exponential));
+        assert_eq!((spec.loss, spec.duplicate, spec.reorder, spec.corrupt, spec.seed), (0.1, 0.5, 0.01, 0.0, 7));
+        assert_eq!(parse_simulation("").unwrap().latency_ms, SimulationSpec::default().latency_ms);
+    }
+
+    #[test]
+    fn rejects_bad_specs() {
+        for (spec, error) in [
+            ("latency", "Expected key=value"),
+            ("speed=1", "Unknown setting 'speed'"),
+            ("latency=-5", "Invalid time '-5'"),
+            ("jitter=fast", "Invalid time 'fast'"),
+            ("dist=pareto", "Unknown distribution 'pareto'"),
+            ("loss=150%", "Invalid probability '150%'"),
+            ("dup=2", "Invalid probability '2'"),
+            ("seed=-1", "Invalid seed '-1'"),
+        ] {
+            let message = parse_simulation(spec).unwrap_err();
+            assert!(message.starts_with(error), "{}: {}", spec, message);
+        }
+    }
+
+    #[test]
+    fn clean_network_answers_at_the_latency() {
+        let stats = simulate("latency=25", 10);
+        assert_eq!((stats.packets_sent, stats.packets_received, stats.duplicates, stats.corrupted), (10, 10, 0, 0));
+        assert_eq!((stats.rtt_min, stats.rtt_max), (25.0, 25.0));
+    }
+
+    #[test]
+    fn loses_replies() {
+        assert_eq!(simulate("loss=100%", 10).packets_received, 0);
+        let stats = simulate("loss=30%,seed=3", 100);
+        assert!((50..90).contains(&stats.packets_received), "{} received", stats.packets_received);
+    }
+
+    #[test]
+    fn duplicates_replies() {
+        let stats = simulate("dup=100%", 10);
+        // The run ends as soon as every probe is answered, before the last probe's copy turns up
+        assert_eq!((stats.packets_received, stats.duplicates), (10, 9));
+    }
+
+    #[test]
+    fn reordered_replies_land_behind_the_next_probe() {
+        // A reordered reply is held back an interval and a half, so the next probe's reply overtakes it
+        let (stats, received) = simulate_with(&config(10), "latency=10,reorder=50%");
+        let order: Vec<u16> = received.iter().map(|datagram| sequence(datagram)).collect();
+        assert_eq!(order, [1, 0, 2, 3, 4, 6, 5, 7, 8, 9]);
+        // Each reply timed against its own probe, however late it was read
+        assert_eq!(stats.rtt_history, [10.0, 160.0, 10.0, 160.0, 160.0, 10.0, 160.0, 10.0, 160.0, 160.0]);
+        assert_eq!((stats.packets_received, stats.duplicates), (10, 0));
+    }
+
+    #[test]
+    fn replies_echo_the_tos_and_options() {
+        let mut config = config(3);
+        config.tos = Some(0xb8);
+        config.ip_option = Some(IpOption::RecordRoute);
+        let (stats, received) = simulate_with(&config, "latency=10");
+        assert_eq!(stats.packets_received, 3);
+        for datagram in &received {
+            let ip = Ipv4Packet::new(datagram).unwrap();
+            assert_eq!(ip.get_dscp() << 2 | ip.get_ecn(), 0xb8);
+            assert_eq!(parse_ip_options(ip.get_options_raw()).route, [Ipv4Addr::new(10, 0, 0, 1)]);
+        }
+        assert_eq!(stats.corrupted, 0);
+    }
+
+    #[test]
+    fn corrupted_replies_are_counted() {
+        let stats = simulate("corrupt=100%", 50);
+        // A flipped bit in the identifier or type hides a reply, the rest arrive and are all corrupted
+        assert!(stats.packets_received > 0);
+        assert_eq!(stats.corrupted, stats.packets_received + stats.duplicates);
+        assert_eq!(simulate("corrupt=0", 50).corrupted, 0);
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_run() {
+        let spec = "latency=20,jitter=8,loss=10%,dup=5%,reorder=5%,corrupt=5%,seed=11";
+        let (first, second) = (simulate(spec, 50), simulate(spec, 50));
+        assert_eq!(first.packets_received, second.packets_received);
+        assert_eq!(first.duplicates, second.duplicates);
+        assert_eq!(first.corrupted, second.corrupted);
+        assert_eq!(first.rtt_history, second.rtt_history);
+    }
+}
@@ -0,0 +1,668 @@
+use std::fmt;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+
+// Path MTU discovery behaviour for outgoing IPv4 packets (IP_MTU_DISCOVER)
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PmtuDiscovery {
+    /// Always set the Don't Fragment bit
+    Do,
+    /// Set the Don't Fragment bit, but fragment locally when the path MTU is known to be smaller
+    Want,
+    /// Never set the Don't Fragment bit
+    Dont,
+}
+
+impl PmtuDiscovery {
+    fn as_raw(&self) -> libc::c_int {
+        match self {
+            PmtuDiscovery::Do => libc::IP_PMTUDISC_DO,
+            PmtuDiscovery::Want => libc::IP_PMTUDISC_WANT,
+            PmtuDiscovery::Dont => libc::IP_PMTUDISC_DONT,
+        }
+    }
+}
+
+// Set an integer socket option on a raw file descriptor
+pub fn set_int_option(fd: RawFd, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Read an integer socket option from a raw file descriptor
+pub fn get_int_option(fd: RawFd, level: libc::c_int, name: libc::c_int) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            level,
+            name,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(value)
+}
+
+// Control whether the Don't Fragment bit is set on outgoing IPv4 packets
+pub fn set_mtu_discover(fd: RawFd, mode: PmtuDiscovery) -> io::Result<()> {
+    set_int_option(fd, libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, mode.as_raw())
+}
+
+// MTU the kernel currently uses on the route to `ip`: the outgoing interface MTU,
+// or a smaller path MTU if one has already been learned
+pub fn route_mtu(ip: IpAddr) -> io::Result<usize> {
+    let bind_addr = match ip {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+
+    // A connected UDP socket picks the route without sending anything
+    let socket = UdpSocket::bind(SocketAddr::new(bind_addr, 0))?;
+    socket.connect(SocketAddr::new(ip, 9))?;
+
+    let mtu = match ip {
+        IpAddr::V4(_) => get_int_option(socket.as_raw_fd(), libc::IPPROTO_IP, libc::IP_MTU)?,
+        IpAddr::V6(_) => get_int_option(socket.as_raw_fd(), libc::IPPROTO_IPV6, libc::IPV6_MTU)?,
+    };
+    Ok(mtu as usize)
+}
+
+// Source address the kernel would pick for packets to `ip`
+pub fn route_source(ip: IpAddr) -> io::Result<IpAddr> {
+    let bind_addr = match ip {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let socket = UdpSocket::bind(SocketAddr::new(bind_addr, 0))?;
+    socket.connect(SocketAddr::new(ip, 9))?;
+    Ok(socket.local_addr()?.ip())
+}
+
+// Set the IPv4 options sent in the header of every outgoing packet (IP_OPTIONS)
+pub fn set_ip_options(fd: RawFd, options: &[u8]) -> io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::IPPROTO_IP,
+            libc::IP_OPTIONS,
+            options.as_ptr() as *const libc::c_void,
+            options.len() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Which kernel timestamps a socket delivers
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KernelTimestamps {
+    pub rx: bool,
+    pub tx: bool,
+}
+
+// Ask the kernel for software receive and transmit timestamps (SO_TIMESTAMPING), falling
+// back to receive timestamps only (SO_TIMESTAMPNS) when that isn't available
+pub fn enable_timestamps(fd: RawFd) -> KernelTimestamps {
+    let flags = libc::SOF_TIMESTAMPING_RX_SOFTWARE
+        | libc::SOF_TIMESTAMPING_TX_SOFTWARE
+        | libc::SOF_TIMESTAMPING_SOFTWARE;
+    if set_int_option(fd, libc::SOL_SOCKET, libc::SO_TIMESTAMPING, flags as libc::c_int).is_ok() {
+        return KernelTimestamps { rx: true, tx: true };
+    }
+    if set_int_option(fd, libc::SOL_SOCKET, libc::SO_TIMESTAMPNS, 1).is_ok() {
+        return KernelTimestamps { rx: true, tx: false };
+    }
+    KernelTimestamps::default()
+}
+
+// A datagram read from a raw socket
+pub struct Received {
+    pub len: usize,
+    pub from: IpAddr,
+    // When the kernel received it, if receive timestamps are enabled
+    pub kernel_time: Option<SystemTime>,
+}
+
+// Space for the control messages carrying a timestamp
+const CONTROL_LEN: usize = 256;
+
+// Convert a kernel timespec (CLOCK_REALTIME) into a SystemTime
+fn timespec_to_system_time(ts: &libc::timespec) -> Option<SystemTime> {
+    if ts.tv_sec == 0 && ts.tv_nsec == 0 {
+        return None;
+    }
+    Some(UNIX_EPOCH + Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+}
+
+// Find a software timestamp among the control messages of a received message
+fn control_timestamp(msg: &libc::msghdr) -> Option<SystemTime> {
+    let mut found = None;
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(msg);
+        while !cmsg.is_null() {
+            let header = &*cmsg;
+            if header.cmsg_level == libc::SOL_SOCKET {
+                let data = libc::CMSG_DATA(cmsg);
+                if header.cmsg_type == libc::SCM_TIMESTAMPING {
+                    // Three timespecs: software, deprecated, hardware
+                    let ts = std::ptr::read_unaligned(data as *const libc::timespec);
+                    found = timespec_to_system_time(&ts).or(found);
+                } else if header.cmsg_type == libc::SCM_TIMESTAMPNS {
+                    let ts = std::ptr::read_unaligned(data as *const libc::timespec);
+                    found = timespec_to_system_time(&ts).or(found);
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(msg, cmsg);
+        }
+    }
+    found
+}
+
+// Receive a message with recvmsg, returning its length, source and any kernel timestamp
+fn recv_with_control(fd: RawFd, buffer: &mut [u8], flags: libc::c_int) -> io::Result<Received> {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut control = [0u8; CONTROL_LEN];
+    let mut iov = libc::iovec {
+        iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buffer.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = &mut storage as *mut libc::sockaddr_storage as *mut libc::c_void;
+    msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = control.len() as _;
+
+    let len = unsafe { libc::recvmsg(fd, &mut msg, flags) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Messages from the error queue carry no address
+    let from = if msg.msg_namelen > 0 {
+        sockaddr_to_ip(&storage)?
+    } else {
+        IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+    };
+    Ok(Received {
+        len: len as usize,
+        from,
+        kernel_time: control_timestamp(&msg),
+    })
+}
+
+// Receive a whole datagram from a raw socket, including the IP header, with its source
+// address and kernel receive timestamp
+pub fn recv_msg(fd: RawFd, buffer: &mut [u8]) -> io::Result<Received> {
+    recv_with_control(fd, buffer, 0)
+}
+
+// Read one transmit timestamp from the socket's error queue, waiting up to `wait` for the
+// kernel to queue one. The sent packet comes back with it so it can be told apart from others.
+pub fn recv_tx_timestamp(fd: RawFd, buffer: &mut [u8], wait: Duration) -> io::Result<Option<Received>> {
+    let mut pollfd = libc::pollfd { fd, events: libc::POLLERR, revents: 0 };
+    let ready = unsafe { libc::poll(&mut pollfd, 1, wait.as_millis() as libc::c_int) };
+    if ready < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if ready == 0 {
+        return Ok(None);
+    }
+    match recv_with_control(fd, buffer, libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT) {
+        Ok(received) => Ok(Some(received)),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// Nanoseconds on the monotonic clock, which isn't affected by the wall clock being stepped
+pub fn monotonic_ns() -> u64 {
+    let mut ts: libc::timespec = unsafe { mem::zeroed() };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+// Convert a kernel (wall clock) timestamp to the monotonic clock, by how long ago it was
+pub fn wall_to_monotonic_ns(time: SystemTime) -> u64 {
+    let now_mono = monotonic_ns();
+    let age = SystemTime::now().duration_since(time).unwrap_or_default();
+    now_mono.saturating_sub(age.as_nanos() as u64)
+}
+
+// And back again, for writing monotonic times out as wall clock times
+pub fn monotonic_to_wall(ns: u64) -> SystemTime {
+    let age = monotonic_ns().saturating_sub(ns);
+    SystemTime::now() - Duration::from_nanos(age)
+}
+
+// Extract the IP address from a socket address filled in by the kernel
+fn sockaddr_to_ip(storage: &libc::sockaddr_storage) -> io::Result<IpAddr> {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let addr = unsafe { &*(storage as *const libc::sockaddr_storage as *const libc::sockaddr_in) };
+            Ok(IpAddr::V4(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr))))
+        },
+        libc::AF_INET6 => {
+            let addr = unsafe { &*(storage as *const libc::sockaddr_storage as *const libc::sockaddr_in6) };
+            Ok(IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr)))
+        },
+        family => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unexpected address family {}", family))),
+    }
+}
+
+// Set the TOS byte (IPv4) or traffic class (IPv6) of outgoing packets
+pub fn set_tos(fd: RawFd, ip: IpAddr, tos: u8) -> io::Result<()> {
+    match ip {
+        IpAddr::V4(_) => set_int_option(fd, libc::IPPROTO_IP, libc::IP_TOS, tos as libc::c_int),
+        IpAddr::V6(_) => set_int_option(fd, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, tos as libc::c_int),
+    }
+}
+
+// Where probes are sent from: a network interface or a local source address
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SourceBinding {
+    Device(String),
+    Address(IpAddr),
+}
+
+impl SourceBinding {
+    // Treat anything that parses as an IP address as a source address, otherwise an interface name
+    pub fn parse(value: &str) -> Self {
+        match value.parse::<IpAddr>() {
+            Ok(ip) => SourceBinding::Address(ip),
+            Err(_) => SourceBinding::Device(value.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for SourceBinding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SourceBinding::Device(name) => write!(f, "{}", name),
+            SourceBinding::Address(ip) => write!(f, "{}", ip),
+        }
+    }
+}
+
+// Restrict a socket to a single network interface (SO_BINDTODEVICE)
+pub fn bind_to_device(fd: RawFd, name: &str) -> io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            name.as_ptr() as *const libc::c_void,
+            name.len() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Bind a socket to a local source address, leaving the port for the kernel to pick
+pub fn bind_to_address(fd: RawFd, ip: IpAddr) -> io::Result<()> {
+    let addr = socket2::SockAddr::from(SocketAddr::new(ip, 0));
+    let result = unsafe { libc::bind(fd, addr.as_ptr(), addr.len()) };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Set the firewall mark used by policy routing (SO_MARK)
+pub fn set_mark(fd: RawFd, mark: u32) -> io::Result<()> {
+    set_int_option(fd, libc::SOL_SOCKET, libc::SO_MARK, mark as libc::c_int)
+}
+
+// Apply the configured source interface or address and firewall mark to an unbound socket
+pub fn apply_source(fd: RawFd, source: Option<&SourceBinding>, mark: Option<u32>) -> io::Result<()> {
+    match source {
+        Some(SourceBinding::Device(name)) => bind_to_device(fd, name)?,
+        Some(SourceBinding::Address(ip)) => bind_to_address(fd, *ip)?,
+        None => {}
+    }
+    if let Some(mark) = mark {
+        set_mark(fd, mark)?;
+    }
+    Ok(())
+}
+
+// Send only to directly attached hosts, bypassing the routing table (SO_DONTROUTE)
+pub fn set_dont_route(fd: RawFd) -> io::Result<()> {
+    set_int_option(fd, libc::SOL_SOCKET, libc::SO_DONTROUTE, 1)
+}
+
+// Allow sending to broadcast addresses (SO_BROADCAST)
+pub fn set_broadcast(fd: RawFd) -> io::Result<()> {
+    set_int_option(fd, libc::SOL_SOCKET, libc::SO_BROADCAST, 1)
+}
+
+// Make reads and writes on a socket return WouldBlock instead of waiting
+pub fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// An eventfd that wakes up a poll loop from another thread or a signal handler
+pub struct Waker {
+    fd: RawFd,
+}
+
+impl Waker {
+    pub fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Waker { fd })
+    }
+
+    pub fn wake(&self) {
+        let one: u64 = 1;
+        unsafe { libc::write(self.fd, &one as *const u64 as *const libc::c_void, mem::size_of::<u64>()) };
+    }
+}
+
+impl AsRawFd for Waker {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for Waker {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+// Wait until any of the descriptors is readable or has a queued error, or until the timeout
+// passes. The timeout has nanosecond precision (ppoll). Returns which descriptors are ready;
+// a wait interrupted by a signal returns none.
+pub fn wait_readable(fds: &[RawFd], timeout: Option<Duration>) -> io::Result<Vec<bool>> {
+    let mut pollfds: Vec<libc::pollfd> = fds.iter()
+        .map(|&fd| libc::pollfd { fd, events: libc::POLLIN, revents: 0 })
+        .collect();
+    let timespec = timeout.map(|timeout| libc::timespec {
+        tv_sec: timeout.as_secs() as libc::time_t,
+        tv_nsec: timeout.subsec_nanos() as libc::c_long,
+    });
+    let timespec_ptr = timespec.as_ref().map_or(std::ptr::null(), |ts| ts as *const libc::timespec);
+
+    let ready = unsafe { libc::ppoll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, timespec_ptr, std::ptr::null()) };
+    if ready < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::Interrupted {
+            return Ok(vec![false; fds.len()]);
+        }
+        return Err(err);
+    }
+    Ok(pollfds.iter().map(|p| p.revents & (libc::POLLIN | libc::POLLERR) != 0).collect())
+}
+
+// Have received datagrams report how many the socket has dropped for lack of buffer space (SO_RXQ_OVFL)
+pub fn enable_drop_count(fd: RawFd) -> io::Result<()> {
+    set_int_option(fd, libc::SOL_SOCKET, libc::SO_RXQ_OVFL, 1)
+}
+
+// Send a batch of datagrams to one destination with a single sendmmsg call, returning how many went out
+pub fn send_batch(fd: RawFd, packets: &[&[u8]], dest: IpAddr) -> io::Result<usize> {
+    let addr = socket2::SockAddr::from(SocketAddr::new(dest, 0));
+    let mut iovecs: Vec<libc::iovec> = packets.iter()
+        .map(|packet| libc::iovec { iov_base: packet.as_ptr() as *mut libc::c_void, iov_len: packet.len() })
+        .collect();
+    let mut messages: Vec<libc::mmsghdr> = iovecs.iter_mut()
+        .map(|iov| {
+            let mut message: libc::mmsghdr = unsafe { mem::zeroed() };
+            message.msg_hdr.msg_name = addr.as_ptr() as *mut libc::c_void;
+            message.msg_hdr.msg_namelen = addr.len();
+            message.msg_hdr.msg_iov = iov;
+            message.msg_hdr.msg_iovlen = 1;
+            message
+        })
+        .collect();
+
+    let sent = unsafe { libc::sendmmsg(fd, messages.as_mut_ptr(), messages.len() as libc::c_uint, 0) };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(sent as usize)
+}
+
+// Datagrams read by one recvmmsg call
+pub struct ReceivedBatch {
+    // Length and source of each datagram, in the order of the buffers they were read into
+    pub datagrams: Vec<(usize, IpAddr)>,
+    // The socket's running count of dropped datagrams, if enable_drop_count is on
+    pub drops: Option<u32>,
+}
+
+// Running drop count carried in a received message's control data
+fn control_drop_count(msg: &libc::msghdr) -> Option<u32> {
+    let mut drops = None;
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(msg);
+        while !cmsg.is_null() {
+            let header = &*cmsg;
+            if header.cmsg_level == libc::SOL_SOCKET && header.cmsg_type == libc::SO_RXQ_OVFL {
+                drops = Some(std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const u32));
+            }
+            cmsg = libc::CMSG_NXTHDR(msg, cmsg);
+        }
+    }
+    drops
+}
+
+// Read up to one datagram per buffer with a single recvmmsg call, without waiting
+pub fn recv_batch(fd: RawFd, buffers: &mut [Vec<u8>]) -> io::Result<ReceivedBatch> {
+    let count = buffers.len();
+    let mut storages: Vec<libc::sockaddr_storage> = vec![unsafe { mem::zeroed() }; count];
+    let mut controls = vec![[0u8; CONTROL_LEN]; count];
+    let mut iovecs: Vec<libc::iovec> = buffers.iter_mut()
+        .map(|buffer| libc::iovec { iov_base: buffer.as_mut_ptr() as *mut libc::c_void, iov_len: buffer.len() })
+        .collect();
+    let mut messages: Vec<libc::mmsghdr> = (0..count)
+        .map(|i| {
+            let mut message: libc::mmsghdr = unsafe { mem::zeroed() };
+            message.msg_hdr.msg_name = &mut storages[i] as *mut libc::sockaddr_storage as *mut libc::c_void;
+            message.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            message.msg_hdr.msg_iov = &mut iovecs[i];
+            message.msg_hdr.msg_iovlen = 1;
+            message.msg_hdr.msg_control = controls[i].as_mut_ptr() as *mut libc::c_void;
+            message.msg_hdr.msg_controllen = CONTROL_LEN as _;
+            message
+        })
+        .collect();
+
+    let received = unsafe {
+        libc::recvmmsg(fd, messages.as_mut_ptr(), count as libc::c_uint, libc::MSG_DONTWAIT, std::ptr::null_mut())
+    };
+    if received < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::WouldBlock {
+            return Ok(ReceivedBatch { datagrams: Vec::new(), drops: None });
+        }
+        return Err(err);
+    }
+
+    let mut batch = ReceivedBatch { datagrams: Vec::with_capacity(received as usize), drops: None };
+    for (message, storage) in messages.iter().zip(&storages).take(received as usize) {
+        batch.datagrams.push((message.msg_len as usize, sockaddr_to_ip(storage)?));
+        batch.drops = control_drop_count(&message.msg_hdr).or(batch.drops);
+    }
+    Ok(batch)
+}
+
+// Offset of the identifier in an ICMP echo or timestamp message
+const ICMP_ID_OFFSET: u32 = 4;
+
+// Length of an ICMP error's own header, after which it quotes the datagram that caused it
+const ICMP_ERROR_HEADER_LEN: u32 = 8;
+
+// A classic BPF instruction
+fn bpf_statement(code: u32, k: u32) -> libc::sock_filter {
+    libc::sock_filter { code: code as u16, jt: 0, jf: 0, k }
+}
+
+// A classic BPF conditional jump, with offsets relative to the next instruction
+fn bpf_jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter { code: code as u16, jt, jf, k }
+}
+
+// Attach a classic BPF filter (SO_ATTACH_FILTER) so the kernel only hands this raw ICMP socket
+// replies of the given types carrying our identifier, and errors of the given types quoting one
+// of our probes. Everything else on the host is dropped before it reaches us.
+pub fn attach_icmp_filter(fd: RawFd, identifier: u16, reply_types: &[u8], error_types: &[u8]) -> io::Result<()> {
+    let type_checks = reply_types.len() + error_types.len();
+    // Layout after the type checks: drop, reply id check (2), error id check (7), accept, drop
+    let drop_unmatched = type_checks + 2;
+    let check_reply = drop_unmatched + 1;
+    let check_error = check_reply + 2;
+    let accept = check_error + 7;
+    let drop = accept + 1;
+    let jump_to = |from: usize, to: usize| (to - from - 1) as u8;
+
+    let mut program = vec![
+        // X = length of the IPv4 header, A = ICMP type
+        bpf_statement(libc::BPF_LDX | libc::BPF_B | libc::BPF_MSH, 0),
+        bpf_statement(libc::BPF_LD | libc::BPF_B | libc::BPF_IND, 0),
+    ];
+    for &icmp_type in reply_types {
+        let pc = program.len();
+        program.push(bpf_jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, icmp_type as u32, jump_to(pc, check_reply), 0));
+    }
+    for &icmp_type in error_types {
+        let pc = program.len();
+        program.push(bpf_jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, icmp_type as u32, jump_to(pc, check_error), 0));
+    }
+    program.push(bpf_statement(libc::BPF_RET | libc::BPF_K, 0));
+    let check_identifier = |program: &mut Vec<libc::sock_filter>| {
+        let pc = program.len();
+        program.push(bpf_jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, identifier as u32,
+                              jump_to(pc, accept), jump_to(pc, drop)));
+    };
+    program.push(bpf_statement(libc::BPF_LD | libc::BPF_H | libc::BPF_IND, ICMP_ID_OFFSET));
+    check_identifier(&mut program);
+    // The quoted header may carry options too, so step X past it by its own IHL before
+    // reading the quoted identifier
+    program.extend([
+        bpf_statement(libc::BPF_LD | libc::BPF_B | libc::BPF_IND, ICMP_ERROR_HEADER_LEN),
+        bpf_statement(libc::BPF_ALU | libc::BPF_AND | libc::BPF_K, 0x0f),
+        bpf_statement(libc::BPF_ALU | libc::BPF_LSH | libc::BPF_K, 2),
+        bpf_statement(libc::BPF_ALU | libc::BPF_ADD | libc::BPF_X, 0),
+        bpf_statement(libc::BPF_MISC | libc::BPF_TAX, 0),
+        bpf_statement(libc::BPF_LD | libc::BPF_H | libc::BPF_IND, ICMP_ERROR_HEADER_LEN + ICMP_ID_OFFSET),
+    ]);
+    check_identifier(&mut program);
+    debug_assert_eq!(program.len(), accept);
+    program.push(bpf_statement(libc::BPF_RET | libc::BPF_K, u32::MAX));
+    program.push(bpf_statement(libc::BPF_RET | libc::BPF_K, 0));
+
+    let fprog = libc::sock_fprog { len: program.len() as u16, filter: program.as_mut_ptr() };
+    let result = unsafe {
+        libc::setsockopt(fd, libc::SOL_SOCKET, libc::SO_ATTACH_FILTER,
+                         &fprog as *const libc::sock_fprog as *const libc::c_void,
+                         mem::size_of::<libc::sock_fprog>() as libc::socklen_t)
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixDatagram;
+
+    use pnet::packet::icmp::{IcmpCode, IcmpType, IcmpTypes};
+
+    use crate::packet::{build_icmp_message, build_ipv4_datagram, IpOption};
+
+    const IDENTIFIER: u16 = 0x4242;
+
+    fn icmp(icmp_type: IcmpType, rest_of_header: [u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut message = vec![0u8; 8 + payload.len()];
+        build_icmp_message(&mut message, icmp_type, IcmpCode(0), rest_of_header, payload);
+        message
+    }
+
+    // An echo message with the given identifier, in a datagram with or without IP options
+    fn echo(icmp_type: IcmpType, identifier: u16, options: &[u8]) -> Vec<u8> {
+        let [high, low] = identifier.to_be_bytes();
+        let message = icmp(icmp_type, [high, low, 0, 1], &[0; 16]);
+        build_ipv4_datagram(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2), 64, 0, options, &message)
+    }
+
+    // A Destination Unreachable error quoting the header and first 8 bytes of a probe
+    fn unreachable(probe: &[u8], options: &[u8]) -> Vec<u8> {
+        let quoted = &probe[..(probe[0] & 0x0f) as usize * 4 + 8];
+        let message = icmp(IcmpTypes::DestinationUnreachable, [0, 0, 5, 220], quoted);
+        build_ipv4_datagram(Ipv4Addr::new(10, 0, 0, 254), Ipv4Addr::new(10, 0, 0, 2), 64, 0, options, &message)
+    }
+
+    // Which datagrams get through the filter. A Unix datagram socket runs it over the whole
+    // datagram, just as a raw ICMP socket runs it from the IPv4 header on.
+    fn passed(datagrams: &[Vec<u8>]) -> Vec<bool> {
+        let (sender, receiver) = UnixDatagram::pair().unwrap();
+        attach_icmp_filter(receiver.as_raw_fd(), IDENTIFIER, &[IcmpTypes::EchoReply.0],
+                           &[IcmpTypes::DestinationUnreachable.0]).unwrap();
+        receiver.set_nonblocking(true).unwrap();
+        for datagram in datagrams {
+            sender.send(datagram).unwrap();
+        }
+        let mut received = Vec::new();
+        let mut buffer = [0u8; 256];
+        while let Ok(len) = receiver.recv(&mut buffer) {
+            received.push(buffer[..len].to_vec());
+        }
+        datagrams.iter().map(|datagram| received.contains(datagram)).collect()
+    }
+
+    #[test]
+    fn filter_passes_our_replies_and_errors_whatever_their_options() {
+        let options = IpOption::RecordRoute.to_bytes();
+        let probe = echo(IcmpTypes::EchoRequest, IDENTIFIER, &[]);
+        let probe_with_options = echo(IcmpTypes::EchoRequest, IDENTIFIER, &options);
+        let other_probe = echo(IcmpTypes::EchoRequest, 7, &options);
+        let datagrams = [
+            echo(IcmpTypes::EchoReply, IDENTIFIER, &[]),
+            echo(IcmpTypes::EchoReply, IDENTIFIER, &options),
+            unreachable(&probe, &[]),
+            unreachable(&probe_with_options, &[]),
+            unreachable(&probe_with_options, &options),
+            echo(IcmpTypes::EchoReply, 7, &[]),
+            unreachable(&other_probe, &options),
+            probe,
+        ];
+        assert_eq!(passed(&datagrams), [true, true, true, true, true, false, false, false]);
+    }
+}
@@ -4,7 +4,12 @@ use std::collections::VecDeque;
 pub struct PingStats {
     pub packets_sent: u64,
     pub packets_received: u64,
+    pub duplicates: u64,
+    // Replies with a bad checksum, or data other than what was sent that isn't another server's art
+    pub corrupted: u64,
     pub rtt_sum: f64,
+    // Sum of the differences between consecutive RTTs, for jitter
+    pub rtt_delta_sum: f64,
     pub rtt_min: f64,
     pub rtt_max: f64,
     pub rtt_history: VecDeque<f64>,
@@ -15,7 +20,10 @@ impl PingStats {
         PingStats {
             packets_sent: 0,
             packets_received: 0,
+            duplicates: 0,
+            corrupted: 0,
             rtt_sum: 0.0,
+            rtt_delta_sum: 0.0,
             rtt_min: f64::MAX,
             rtt_max: 0.0,
             rtt_history: VecDeque::with_capacity(10),
@@ -27,6 +35,9 @@ impl PingStats {
         self.rtt_sum += rtt;
         self.rtt_min = self.rtt_min.min(rtt);
         self.rtt_max = self.rtt_max.max(rtt);
+        if let Some(&previous) = self.rtt_history.back() {
+            self.rtt_delta_sum += (rtt - previous).abs();
+        }
         
         // Add to history (used for moving average)
         self.rtt_history.push_back(rtt);
@@ -42,10 +53,57 @@ impl PingStats {
         self.rtt_sum / self.packets_received as f64
     }
 
+    // Mean difference between consecutive RTTs
+    pub fn jitter(&self) -> f64 {
+        if self.packets_received < 2 {
+            return 0.0;
+        }
+        self.rtt_delta_sum / (self.packets_received - 1) as f64
+    }
+
     pub fn packet_loss(&self) -> f64 {
         if self.packets_sent == 0 {
             return 0.0;
         }
         ((self.packets_sent - self.packets_received) as f64 / self.packets_sent as f64) * 100.0
     }
-} 
\ No newline at end of file
+}
+
+// Least-squares fit of y = slope * x + intercept, None if x doesn't vary
+pub fn linear_regression(points: &[(f64, f64)]) -> Option<(f64, f64)> {
+    if points.len() < 2 {
+        return None;
+    }
+    
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / n;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+    
+    let covariance: f64 = points.iter().map(|(x, y)| (x - mean_x) * (y - mean_y)).sum();
+    let variance: f64 = points.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+    if variance == 0.0 {
+        return None;
+    }
+    
+    let slope = covariance / variance;
+    Some((slope, mean_y - slope * mean_x))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn regression_recovers_a_known_line() {
+        let points: Vec<(f64, f64)> = (0..5).map(|x| (x as f64 * 100.0, 0.002 * x as f64 * 100.0 + 1.5)).collect();
+        let (slope, intercept) = linear_regression(&points).unwrap();
+        assert!((slope - 0.002).abs() < 1e-12);
+        assert!((intercept - 1.5).abs() < 1e-12);
+    }
+
+    #[test]
+    fn regression_needs_varying_x() {
+        assert_eq!(linear_regression(&[(1.0, 2.0)]), None);
+        assert_eq!(linear_regression(&[(3.0, 1.0), (3.0, 5.0)]), None);
+    }
+}
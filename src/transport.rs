@@ -0,0 +1,181 @@
+use std::io;
+use std::net::IpAddr;
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow};
+use pnet::packet::icmp::{IcmpPacket, IcmpTypes};
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet_transport::{transport_channel, TransportChannelType, TransportReceiver, TransportSender};
+use pnet_transport::TransportProtocol::Ipv4;
+
+use crate::config::PingConfig;
+use crate::socket::{apply_source, attach_icmp_filter, enable_timestamps, monotonic_ns, recv_msg, recv_tx_timestamp, set_broadcast,
+                    set_dont_route, set_ip_options, set_mtu_discover, set_nonblocking, set_tos, wait_readable,
+                    wall_to_monotonic_ns, KernelTimestamps, Received};
+
+// Where the ping loop sends its probes and reads replies from: raw sockets for real pings, or a
+// simulated network that needs no privileges
+pub trait ProbeTransport {
+    // Send one ICMP message to the destination, returning the bytes sent
+    fn send(&mut self, packet: &[u8], dest: IpAddr) -> io::Result<usize>;
+
+    // Read one whole IPv4 datagram into `buffer`, failing with WouldBlock when none is waiting
+    fn recv(&mut self, buffer: &mut [u8]) -> io::Result<Received>;
+
+    // Wait until a datagram may be waiting, `wake` becomes readable or the timeout passes,
+    // returning whether there's something to read
+    fn wait(&mut self, wake: RawFd, timeout: Duration) -> io::Result<bool>;
+
+    // Transmit times of our probes that have come in since the last call, as sequence numbers
+    // and monotonic nanoseconds
+    fn tx_timestamps(&mut self) -> Vec<(u16, u64)>;
+
+    // Which of the times above the transport reports
+    fn timestamps(&self) -> KernelTimestamps;
+
+    // The time probes are sent and replies received at, in monotonic nanoseconds
+    fn now(&self) -> u64;
+}
+
+// Length of an Ethernet header, and the EtherType of IPv4
+const ETHERNET_HEADER_LEN: usize = 14;
+const ETHERTYPE_IPV4: [u8; 2] = [0x08, 0x00];
+
+// Identifier and sequence of the probe a transmit timestamp came back with
+fn looped_probe(packet: &[u8]) -> Option<(u16, u16)> {
+    // The packet comes back as the device sent it, so skip an Ethernet header if there is one
+    let is_icmp = |bytes: &[u8]| Ipv4Packet::new(bytes)
+        .is_some_and(|ip| ip.get_version() == 4 && ip.get_next_level_protocol().0 == 1);
+    let ip_bytes = if is_icmp(packet) {
+        packet
+    } else if packet.get(12..14) == Some(&ETHERTYPE_IPV4[..]) {
+        packet.get(ETHERNET_HEADER_LEN..)?
+    } else {
+        return None;
+    };
+    let ip = Ipv4Packet::new(ip_bytes)?;
+    let header = ip_bytes.get(ip.get_header_length() as usize * 4..)?.get(4..8)?;
+    Some((u16::from_be_bytes([header[0], header[1]]), u16::from_be_bytes([header[2], header[3]])))
+}
+
+// A raw ICMPv4 socket, set up with every socket option the configuration asks for
+pub struct RawSocketTransport {
+    tx: TransportSender,
+    // Kept so the shared socket stays open; replies are read straight off the descriptor
+    _rx: TransportReceiver,
+    identifier: u16,
+    timestamps: KernelTimestamps,
+}
+
+impl RawSocketTransport {
+    pub fn open(config: &PingConfig, identifier: u16) -> Result<Self> {
+        // Create transport channel for ICMP
+        // Protocol 1 is ICMP for IPv4
+        let protocol = Ipv4(pnet::packet::ip::IpNextHeaderProtocol(1));
+        println!("Creating transport channel for ICMP (protocol number: 1)");
+
+        let (tx, rx) = match transport_channel(4096, TransportChannelType::Layer4(protocol)) {
+            Ok((tx, rx)) => {
+                println!("Successfully created transport channel");
+                (tx, rx)
+            },
+            Err(e) => {
+                eprintln!("Error creating transport channel: {}", e);
+                eprintln!("This is likely due to permissions - try running with sudo or as administrator");
+                return Err(anyhow!("Failed to create ICMP socket: {}", e));
+            }
+        };
+        let fd = tx.socket.fd;
+
+        // Send from the requested interface or source address, with the requested firewall mark
+        apply_source(fd, config.source.as_ref(), config.mark)
+            .context("Failed to bind to the source interface or set the firewall mark")?;
+
+        // Control the Don't Fragment bit if requested
+        if let Some(mode) = config.pmtu_discovery {
+            set_mtu_discover(fd, mode).context("Failed to set path MTU discovery mode")?;
+        }
+
+        // Bypass the routing table and send only to directly attached hosts
+        if config.bypass_route {
+            set_dont_route(fd).context("Failed to bypass routing")?;
+        }
+
+        // Allow pinging broadcast addresses
+        if config.broadcast {
+            set_broadcast(fd).context("Failed to enable broadcast")?;
+        }
+
+        // Ask routers to record the route or timestamps in every probe
+        if let Some(option) = config.ip_option {
+            set_ip_options(fd, &option.to_bytes()).context("Failed to set IP options")?;
+        }
+
+        // Mark probes with the requested TOS / DSCP
+        if let Some(tos) = config.tos {
+            set_tos(fd, config.ip_addr, tos).context("Failed to set TOS")?;
+        }
+
+        // Use kernel timestamps for RTT where the socket supports them
+        let timestamps = enable_timestamps(fd);
+
+        // Have the kernel drop ICMP traffic that isn't a reply to us, falling back to filtering here
+        let reply_types = [IcmpTypes::EchoReply.0, IcmpTypes::TimestampReply.0];
+        match attach_icmp_filter(fd, identifier, &reply_types, &[]) {
+            Ok(()) => println!("Attached BPF filter for identifier {}", identifier),
+            Err(e) => eprintln!("Failed to attach BPF filter, filtering replies in userspace: {}", e),
+        }
+
+        // The ping loop polls, so reads must never block
+        set_nonblocking(fd).context("Failed to make the ICMP socket non-blocking")?;
+
+        Ok(RawSocketTransport { tx, _rx: rx, identifier, timestamps })
+    }
+
+    fn fd(&self) -> RawFd {
+        self.tx.socket.fd
+    }
+}
+
+impl ProbeTransport for RawSocketTransport {
+    fn send(&mut self, packet: &[u8], dest: IpAddr) -> io::Result<usize> {
+        let packet = IcmpPacket::new(packet)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "ICMP message too short"))?;
+        self.tx.send_to(packet, dest)
+    }
+
+    fn recv(&mut self, buffer: &mut [u8]) -> io::Result<Received> {
+        recv_msg(self.fd(), buffer)
+    }
+
+    fn wait(&mut self, wake: RawFd, timeout: Duration) -> io::Result<bool> {
+        wait_readable(&[self.fd(), wake], Some(timeout)).map(|ready| ready[0])
+    }
+
+    // Read transmit timestamps off the socket's error queue, keeping those of our own probes
+    fn tx_timestamps(&mut self) -> Vec<(u16, u64)> {
+        let mut times = Vec::new();
+        if !self.timestamps.tx {
+            return times;
+        }
+        let mut buffer = [0u8; 128];
+        while let Ok(Some(received)) = recv_tx_timestamp(self.fd(), &mut buffer, Duration::ZERO) {
+            let (Some(kernel_time), Some((id, seq))) = (received.kernel_time, looped_probe(&buffer[..received.len.min(buffer.len())])) else {
+                continue;
+            };
+            if id == self.identifier {
+                times.push((seq, wall_to_monotonic_ns(kernel_time)));
+            }
+        }
+        times
+    }
+
+    fn timestamps(&self) -> KernelTimestamps {
+        self.timestamps
+    }
+
+    fn now(&self) -> u64 {
+        monotonic_ns()
+    }
+}